@@ -0,0 +1,120 @@
+use nix::sys::signal::{killpg, raise, signal, SigHandler, SigSet, SigmaskHow, Signal};
+use nix::unistd::Pid;
+use std::io;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus};
+
+/// Signals relayed from flatvodka to the app's process group.
+const FORWARDED: [Signal; 4] = [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP, Signal::SIGWINCH];
+
+/// Forwarded signals that end the run when received before the app starts.
+const FATAL: [Signal; 3] = [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP];
+
+/// How the app ended, to be passed on as flatvodka's own exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    Code(i32),
+    Signal(Signal),
+}
+
+impl Exit {
+    pub fn from_status(status: ExitStatus) -> Self {
+        match (status.code(), status.signal().and_then(|s| Signal::try_from(s).ok())) {
+            (Some(code), _) => Exit::Code(code),
+            // Re-raising would leave a core of flatvodka rather than the app.
+            (None, Some(sig)) if status.core_dumped() => Exit::Code(128 + sig as i32),
+            (None, Some(sig)) => Exit::Signal(sig),
+            (None, None) => Exit::Code(1),
+        }
+    }
+
+    /// Exits with the code, or dies from the signal the app died from.
+    pub fn exit(self) -> ! {
+        match self {
+            Exit::Code(code) => std::process::exit(code),
+            Exit::Signal(sig) => {
+                unsafe {
+                    let _ = signal(sig, SigHandler::SigDfl);
+                }
+                let mut set = SigSet::empty();
+                set.add(sig);
+                let _ = set.thread_unblock();
+                let _ = raise(sig);
+                std::process::exit(128 + sig as i32)
+            }
+        }
+    }
+}
+
+fn waited() -> SigSet {
+    let mut set = SigSet::empty();
+    for sig in FORWARDED {
+        set.add(sig);
+    }
+    set.add(Signal::SIGCHLD);
+    set
+}
+
+/// Blocks the forwarded signals (and SIGCHLD, SIGTTOU) so they queue up for
+/// `supervise` instead of killing flatvodka. Returns the previous mask.
+pub fn block_signals() -> SigSet {
+    let mut set = waited();
+    set.add(Signal::SIGTTOU);
+    let mut old = SigSet::empty();
+    let _ = nix::sys::signal::sigprocmask(SigmaskHow::SIG_BLOCK, Some(&set), Some(&mut old));
+    old
+}
+
+/// A fatal signal that arrived while signals were blocked, if any.
+pub fn interrupted() -> Option<Signal> {
+    let mut pending: libc::sigset_t = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sigpending(&mut pending) } != 0 {
+        return None;
+    }
+    FATAL.into_iter().find(|&sig| unsafe { libc::sigismember(&pending, sig as i32) } == 1)
+}
+
+fn stdin_is_foreground() -> bool {
+    unsafe { libc::isatty(0) == 1 && libc::tcgetpgrp(0) == libc::getpgrp() }
+}
+
+/// Puts the child in its own process group, hands it the terminal when we
+/// own it, and restores the signal mask from before `block_signals`.
+pub fn prepare(cmd: &mut Command, old_mask: SigSet) {
+    let foreground = stdin_is_foreground();
+    let old_mask: libc::sigset_t = *old_mask.as_ref();
+    unsafe {
+        cmd.pre_exec(move || {
+            libc::setpgid(0, 0);
+            if foreground {
+                libc::tcsetpgrp(0, libc::getpid());
+            }
+            libc::sigprocmask(libc::SIG_SETMASK, &old_mask, std::ptr::null_mut());
+            Ok(())
+        });
+    }
+}
+
+/// Waits for `child`, forwarding signals to its process group meanwhile, and
+/// takes the terminal back once it is gone.
+pub fn supervise(child: &mut Child) -> io::Result<ExitStatus> {
+    let pgid = Pid::from_raw(child.id() as i32);
+    let set = waited();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        match set.wait() {
+            Ok(Signal::SIGCHLD) | Err(_) => {}
+            Ok(sig) => {
+                let _ = killpg(pgid, sig);
+            }
+        }
+    };
+    unsafe {
+        if libc::isatty(0) == 1 && libc::tcgetpgrp(0) == pgid.as_raw() {
+            libc::tcsetpgrp(0, libc::getpgrp());
+        }
+    }
+    Ok(status)
+}
@@ -0,0 +1,363 @@
+use crate::error::{Error, Result};
+use crate::install::invoking_home;
+use nix::unistd::getuid;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// System-wide configuration, read first.
+pub const SYSTEM_CONFIG: &str = "/usr/local/etc/flatvodka.conf";
+/// Per-user configuration, relative to the user's config directory.
+pub const USER_CONFIG: &str = "flatvodka/config.toml";
+
+/// flatvodka configuration. Both files are TOML with the same keys; values
+/// from the user file override the system file, which overrides the built-in
+/// defaults. Tables are merged key by key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Per-user Flatpak installation, relative to the user's home.
+    pub user_flatpak_dir: String,
+    /// System-wide Flatpak installation.
+    pub system_flatpak_dir: String,
+    /// Remote used for bare app ids and `runtime/...` refs.
+    pub flathub_url: String,
+    /// Jail roots are `<jail-root-prefix><app-id>-<instance>`.
+    pub jail_root_prefix: String,
+    /// Where running instances are recorded.
+    pub state_dir: String,
+    /// ostree binaries tried in order before `ostree` from PATH.
+    pub ostree_binaries: Vec<String>,
+    /// Linux compat root whose mirror of the repo config is fixed up too.
+    pub ostree_compat_root: String,
+    /// Value of `flatpak-version` in `/.flatpak-info`; portals use it to tell
+    /// which sandbox features are available.
+    pub flatpak_version: String,
+    /// Host fonts mapped to /run/host/fonts.
+    pub fonts_dir: String,
+    /// Host icon themes mapped to /run/host/share/icons.
+    pub icons_dir: String,
+    /// Host GTK and other themes mapped to /run/host/share/themes.
+    pub themes_dir: String,
+    /// Host X11 cursors mapped to /run/host/cursors.
+    pub cursors_dir: String,
+    /// xdg-dbus-proxy binary that filters the app's D-Bus traffic.
+    pub dbus_proxy: String,
+    /// Directories scanned for host OpenGL libraries.
+    pub gl_search_dirs: Vec<String>,
+    pub vulkan: VulkanConfig,
+    /// Named host library injection profiles, `[inject.<name>]`.
+    pub inject: BTreeMap<String, InjectProfile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct VulkanConfig {
+    /// Directories holding ICD manifests.
+    pub icd_dirs: Vec<String>,
+    /// Directories holding explicit layer manifests.
+    pub layer_dirs: Vec<String>,
+    /// Where bare `library_path` names from manifests are looked up.
+    pub driver_lib_dirs: Vec<String>,
+}
+
+/// A set of host libraries copied into the jail.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InjectProfile {
+    /// Profile name, filled in from the table key.
+    #[serde(skip)]
+    pub name: String,
+    /// Host directories searched for libraries.
+    pub roots: Vec<String>,
+    /// File name globs (`*`, `?`) always injected from `roots`. Libraries the
+    /// app needs but the runtime lacks are injected from `roots` regardless.
+    #[serde(default)]
+    pub libs: Vec<String>,
+    /// Jail directory, relative to `/`, the libraries are copied to.
+    #[serde(default = "default_target")]
+    pub target: String,
+}
+
+fn default_target() -> String {
+    "lib".to_string()
+}
+
+/// Whether `path` is a non-empty relative path that never steps up with `..`.
+fn is_relative_inside(path: &str) -> bool {
+    !path.is_empty() && Path::new(path).components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+fn strings(items: &[&str]) -> Vec<String> {
+    items.iter().map(|s| s.to_string()).collect()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let mut inject = BTreeMap::new();
+        inject.insert(
+            "default".to_string(),
+            InjectProfile {
+                name: String::new(),
+                roots: strings(&["/compat/ubuntu/lib", "/compat/ubuntu/lib64", "/compat/linux/usr/lib", "/compat/linux/usr/lib64"]),
+                libs: Vec::new(),
+                target: default_target(),
+            },
+        );
+        Config {
+            user_flatpak_dir: ".local/share/flatpak".to_string(),
+            system_flatpak_dir: "/var/db/flatpak".to_string(),
+            flathub_url: "https://dl.flathub.org/repo/".to_string(),
+            jail_root_prefix: "/mnt/flatvodka_".to_string(),
+            state_dir: "/var/run/flatvodka".to_string(),
+            ostree_binaries: strings(&["/compat/ubuntu/usr/bin/ostree", "/compat/linux/usr/bin/ostree"]),
+            ostree_compat_root: "/compat/ubuntu".to_string(),
+            flatpak_version: "1.14.10".to_string(),
+            fonts_dir: "/usr/local/share/fonts".to_string(),
+            icons_dir: "/usr/local/share/icons".to_string(),
+            themes_dir: "/usr/local/share/themes".to_string(),
+            cursors_dir: "/usr/local/lib/X11/icons".to_string(),
+            dbus_proxy: "/usr/local/bin/xdg-dbus-proxy".to_string(),
+            gl_search_dirs: strings(&[
+                "/compat/linux/usr/lib",
+                "/compat/linux/usr/lib64",
+                "/usr/lib",
+                "/usr/lib64",
+                "/lib",
+                "/lib64",
+                "/compat/linux/usr/lib/dri",
+                "/compat/linux/usr/lib64/dri",
+            ]),
+            vulkan: VulkanConfig::default(),
+            inject,
+        }
+    }
+}
+
+impl Default for VulkanConfig {
+    fn default() -> Self {
+        VulkanConfig {
+            icd_dirs: strings(&["/compat/linux/usr/share/vulkan/icd.d", "/usr/share/vulkan/icd.d"]),
+            layer_dirs: strings(&["/compat/linux/usr/share/vulkan/explicit_layer.d", "/usr/share/vulkan/explicit_layer.d"]),
+            driver_lib_dirs: strings(&[
+                "/compat/linux/usr/lib/x86_64-linux-gnu",
+                "/compat/linux/usr/lib64",
+                "/compat/linux/usr/lib",
+            ]),
+        }
+    }
+}
+
+/// Keys the user file may set when flatvodka runs as root. The others name
+/// programs root runs, paths it writes or deletes, or host files it exposes
+/// to the jail, and a user file can be changed by the apps the user runs.
+const USER_KEYS_AS_ROOT: &[&str] = &["flathub-url", "flatpak-version", "user-flatpak-dir"];
+
+/// The invoking user's `~/.config/flatvodka/config.toml`, their home looked
+/// up like `install::invoking_home`. `$XDG_CONFIG_HOME` is only honoured
+/// when not running as root.
+pub fn user_config_path() -> Result<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME").filter(|_| !getuid().is_root()) {
+        Some(dir) => PathBuf::from(dir),
+        None => invoking_home()?.join(".config"),
+    };
+    Ok(base.join(USER_CONFIG))
+}
+
+/// The table in `path`; a missing file is an empty table.
+fn read_table(path: &Path) -> Result<toml::Table, String> {
+    let Ok(content) = fs::read_to_string(path) else {
+        return Ok(toml::Table::new());
+    };
+    content.parse::<toml::Table>().map_err(|e| format!("invalid config {}: {}", path.display(), e))
+}
+
+/// Recursively merges `overlay` into `base`; overlay values win.
+fn merge(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(b)), toml::Value::Table(o)) => merge(b, o),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Loads the built-in defaults, then the system file, then the user file.
+    /// As root, the user file can only set `USER_KEYS_AS_ROOT`.
+    pub fn load() -> Result<Self> {
+        let mut table = Config::default().to_table();
+        merge(&mut table, read_table(Path::new(SYSTEM_CONFIG)).map_err(Error::Config)?);
+        let path = user_config_path()?;
+        let mut user_table = read_table(&path).map_err(Error::Config)?;
+        if getuid().is_root() {
+            user_table.retain(|key, _| {
+                let allowed = USER_KEYS_AS_ROOT.contains(&key);
+                if !allowed {
+                    eprintln!("⚠️  Ignoring {} from {:?}: only {} can be set there when running as root", key, path, SYSTEM_CONFIG);
+                }
+                allowed
+            });
+        }
+        merge(&mut table, user_table);
+        Config::from_table(table).map_err(|e| Error::Config(format!("invalid configuration: {}", e)))
+    }
+
+    pub fn from_table(table: toml::Table) -> Result<Self, String> {
+        let config: Config = toml::Value::Table(table).try_into().map_err(|e: toml::de::Error| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Paths that are joined to a directory must stay inside it.
+    fn validate(&self) -> Result<(), String> {
+        if !is_relative_inside(&self.user_flatpak_dir) {
+            return Err(format!("user-flatpak-dir must be a relative path without '..': {}", self.user_flatpak_dir));
+        }
+        for (name, profile) in &self.inject {
+            if !is_relative_inside(&profile.target) {
+                return Err(format!("inject.{}.target must be a relative path without '..': {}", name, profile.target));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_table(&self) -> toml::Table {
+        toml::Table::try_from(self).expect("config always serializes")
+    }
+
+    /// Looks up an injection profile by name.
+    pub fn inject_profile(&self, name: &str) -> Option<InjectProfile> {
+        let mut profile = self.inject.get(name).cloned()?;
+        profile.name = name.to_string();
+        Some(profile)
+    }
+}
+
+/// Looks up a dotted key (`vulkan.icd-dirs`) in a config table.
+pub fn get_key<'a>(table: &'a toml::Table, key: &str) -> Option<&'a toml::Value> {
+    let mut parts = key.split('.');
+    let mut value = table.get(parts.next()?)?;
+    for part in parts {
+        value = value.as_table()?.get(part)?;
+    }
+    Some(value)
+}
+
+/// Sets a dotted key in `table`, creating intermediate tables. `raw` is parsed
+/// as a TOML value (`["a", "b"]`, `42`) and taken as a plain string otherwise.
+pub fn set_key(table: &mut toml::Table, key: &str, raw: &str) -> Result<(), String> {
+    let value = format!("v = {}", raw)
+    .parse::<toml::Table>()
+    .ok()
+    .and_then(|mut t| t.remove("v"))
+    .unwrap_or_else(|| toml::Value::String(raw.to_string()));
+    let parts: Vec<&str> = key.split('.').collect();
+    let (last, parents) = parts.split_last().ok_or("empty key")?;
+    let mut current = table;
+    for part in parents {
+        current = current
+        .entry(part.to_string())
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+        .as_table_mut()
+        .ok_or_else(|| format!("{} is not a table", part))?;
+    }
+    current.insert(last.to_string(), value);
+    Ok(())
+}
+
+/// Flattens a table into `dotted.key = value` lines, sorted by key.
+pub fn flatten(table: &toml::Table, prefix: &str, out: &mut Vec<(String, String)>) {
+    for (key, value) in table {
+        let full = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        match value {
+            toml::Value::Table(t) => flatten(t, &full, out),
+            v => out.push((full, v.to_string())),
+        }
+    }
+}
+
+/// Writes `key = raw` to the config file at `path`, refusing values that would
+/// make the configuration invalid.
+pub fn set_in_file(path: &Path, key: &str, raw: &str) -> Result<(), String> {
+    let mut file_table = read_table(path)?;
+    set_key(&mut file_table, key, raw)?;
+    let mut effective = Config::default().to_table();
+    merge(&mut effective, file_table.clone());
+    Config::from_table(effective)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let content = toml::to_string_pretty(&file_table).map_err(|e| e.to_string())?;
+    fs::write(path, content).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Shell-style matching of `name` against a pattern with `*` and `?`.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let (p, n): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut pi, mut ni) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ni < n.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == n[ni]) {
+            pi += 1;
+            ni += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ni));
+            pi += 1;
+        } else if let Some((sp, sn)) = star {
+            pi = sp + 1;
+            ni = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_key(key: &str, raw: &str) -> Result<Config, String> {
+        let mut table = Config::default().to_table();
+        set_key(&mut table, key, raw)?;
+        Config::from_table(table)
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(Config::from_table(Config::default().to_table()).is_ok());
+    }
+
+    #[test]
+    fn inject_target_must_stay_in_the_jail() {
+        assert!(with_key("inject.default.target", "lib/nvidia").is_ok());
+        assert!(with_key("inject.default.target", "../../../etc").is_err());
+        assert!(with_key("inject.default.target", "lib/../../etc").is_err());
+        assert!(with_key("inject.default.target", "/etc").is_err());
+        assert!(with_key("inject.default.target", "").is_err());
+    }
+
+    #[test]
+    fn user_flatpak_dir_must_stay_in_the_home() {
+        assert!(with_key("user-flatpak-dir", ".flatpak").is_ok());
+        assert!(with_key("user-flatpak-dir", "/var/db/flatpak").is_err());
+        assert!(with_key("user-flatpak-dir", "../other/.flatpak").is_err());
+    }
+
+    #[test]
+    fn invalid_files_are_errors_not_defaults() {
+        let dir = crate::elf::fixture::scratch_dir("config-invalid");
+        let path = dir.join("config.toml");
+        assert_eq!(read_table(&path).unwrap(), toml::Table::new());
+        fs::write(&path, "flathub-url = [").unwrap();
+        assert!(read_table(&path).is_err());
+        assert!(set_in_file(&path, "flatpak-version", "1.16.0").is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "flathub-url = [");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,348 @@
+use crate::error::{Error, Result};
+use crate::plan::{DbusProxy, Mount, Step};
+use crate::sandbox::Host;
+use nix::fcntl::OFlag;
+use nix::sys::signal::SigSet;
+use nix::unistd::pipe2;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+
+/// The system bus socket, on the host and in the jail.
+pub const SYSTEM_BUS_SOCKET: &str = "/var/run/dbus/system_bus_socket";
+
+/// Host session bus of `uid`: `DBUS_SESSION_BUS_ADDRESS` when set, else the
+/// conventional socket in its runtime directory.
+pub fn session_bus_address(host: &Host, uid: u32) -> Option<String> {
+    if let Some(address) = host.var("DBUS_SESSION_BUS_ADDRESS") {
+        return Some(address.to_string());
+    }
+    let path = format!("/var/run/user/{}/bus", uid);
+    host.path(&path).exists().then(|| format!("unix:path={}", path))
+}
+
+/// Where the host's D-Bus activation files live.
+const SERVICE_DIRS: &[&str] = &["/usr/local/share/dbus-1/services", "/usr/share/dbus-1/services"];
+
+/// Whether the host session bus can start `name` on demand.
+pub fn has_service(host: &Host, name: &str) -> bool {
+    SERVICE_DIRS.iter().any(|d| host.path(d).join(format!("{}.service", name)).exists())
+}
+
+/// Host system bus: `DBUS_SYSTEM_BUS_ADDRESS` when set, else the standard socket.
+pub fn system_bus_address(host: &Host) -> Option<String> {
+    if let Some(address) = host.var("DBUS_SYSTEM_BUS_ADDRESS") {
+        return Some(address.to_string());
+    }
+    host.path(SYSTEM_BUS_SOCKET).exists().then(|| format!("unix:path={}", SYSTEM_BUS_SOCKET))
+}
+
+/// Where proxies create their sockets, in their root and in the jail.
+pub const PROXY_SOCKET_DIR: &str = "/run/flatpak/bus";
+
+/// Host directories a proxy needs to run: the FreeBSD linker and libraries.
+const PROXY_HOST_DIRS: &[&str] = &["/libexec", "/lib", "/usr/lib", "/usr/local/lib"];
+
+/// Root the proxies of the jail at `jail_root` are chrooted to. It lies next
+/// to the jail, so the app cannot reach the host buses through it.
+pub fn proxy_root(jail_root: &Path) -> PathBuf {
+    let mut root = jail_root.as_os_str().to_os_string();
+    root.push(".dbus");
+    PathBuf::from(root)
+}
+
+/// Steps that populate the proxies' root: the host linker, libraries and
+/// `program`, the app's `/.flatpak-info` so that xdg-desktop-portal, which
+/// reads it through the root of the process on the other end of a bus
+/// connection, identifies the app, the host bus sockets, and the jail's
+/// `PROXY_SOCKET_DIR` to listen in.
+pub fn proxy_root_steps(host: &Host, program: &str, proxies: &[DbusProxy], flatpak_info: &str, jail_root: &Path) -> Vec<Step> {
+    let mut steps = vec![Step::Mount(Mount {
+        fstype: "tmpfs".to_string(),
+        source: "tmpfs".to_string(),
+        target: "/".into(),
+        ro: false,
+        file: false,
+        required: true,
+    })];
+    let program_dir = Path::new(program).parent().unwrap_or(Path::new("/"));
+    let dirs = PROXY_HOST_DIRS.iter().map(|d| (host.path(d), Path::new(d))).chain([(host.path(program_dir), program_dir)]);
+    for (source, dir) in dirs {
+        if source.is_dir() && !steps.iter().any(|s| matches!(s, Step::Dir { path, .. } if path == dir)) {
+            steps.push(Step::Dir { path: dir.to_path_buf(), mode: None, owner: None });
+            steps.push(Step::Mount(Mount::nullfs(&source, dir, true)));
+        }
+    }
+    let hints = host.path("/var/run/ld-elf.so.hints");
+    if hints.exists() {
+        steps.push(Step::Copy { source: hints, path: "/var/run/ld-elf.so.hints".into() });
+    }
+    steps.push(Step::Write { path: "/.flatpak-info".into(), content: flatpak_info.to_string(), mode: Some(0o444) });
+    for socket in proxies.iter().filter_map(|p| socket_path(&p.address)) {
+        steps.push(Step::Mount(Mount::nullfs(&host.path(&socket), &socket, false).file()));
+    }
+    steps.push(Step::Dir { path: PROXY_SOCKET_DIR.into(), mode: None, owner: None });
+    let jail_sockets = jail_root.join(PROXY_SOCKET_DIR.trim_start_matches('/'));
+    steps.push(Step::Mount(Mount::nullfs(&jail_sockets, PROXY_SOCKET_DIR, false).required()));
+    steps
+}
+
+/// Socket of the first `unix:path=` entry of a bus address.
+pub fn socket_path(address: &str) -> Option<PathBuf> {
+    address.split(';').find_map(|entry| {
+        let params = entry.strip_prefix("unix:")?;
+        params.split(',').find_map(|kv| kv.strip_prefix("path=")).map(PathBuf::from)
+    })
+}
+
+/// `--see`/`--talk`/`--own` options for the names a bus policy grants.
+fn policy_args(policy: &BTreeMap<String, String>) -> impl Iterator<Item = String> + '_ {
+    policy.iter().filter_map(|(name, level)| match level.as_str() {
+        "see" | "talk" | "own" => Some(format!("--{}={}", level, name)),
+        _ => None,
+    })
+}
+
+/// Filtering options for the session bus: the app may own its own names and
+/// talk to the portals, plus whatever `[Session Bus Policy]` grants.
+pub fn session_filter(app_id: &str, policy: &BTreeMap<String, String>) -> Vec<String> {
+    let mut args = vec![
+        "--filter".to_string(),
+        "--log".to_string(),
+        format!("--own={}.*", app_id),
+        format!("--own=org.mpris.MediaPlayer2.{}.*", app_id),
+        "--talk=org.freedesktop.portal.*".to_string(),
+        "--call=org.freedesktop.portal.*=*".to_string(),
+        "--broadcast=org.freedesktop.portal.*=@/org/freedesktop/portal/*".to_string(),
+    ];
+    args.extend(policy_args(policy));
+    args
+}
+
+/// Filtering options for the system bus: only what `[System Bus Policy]` grants.
+pub fn system_filter(policy: &BTreeMap<String, String>) -> Vec<String> {
+    let mut args = vec!["--filter".to_string(), "--log".to_string()];
+    args.extend(policy_args(policy));
+    args
+}
+
+/// The messages a proxy started with `--log` rejected, with their verdicts.
+/// The proxy logs every message on one line and, when it drops or denies it,
+/// a `*HIDDEN*` or `*DENIED*` verdict on the next.
+fn rejections(out: impl BufRead) -> impl Iterator<Item = (String, String)> {
+    let mut message = String::new();
+    out.lines().map_while(|l| l.ok()).filter_map(move |line| {
+        if line.starts_with('*') && (line.contains("DENIED") || line.contains("HIDDEN")) {
+            Some((message.trim().to_string(), line.trim().to_string()))
+        } else {
+            message = line;
+            None
+        }
+    })
+}
+
+/// Reports what a proxy started with `--log` rejected to `sink`, one line
+/// per message.
+fn log_rejections(out: impl BufRead, label: &str, mut sink: impl Write) {
+    for (message, verdict) in rejections(out) {
+        let _ = writeln!(sink, "🚫 {}: rejected {} {}", label, message, verdict);
+    }
+}
+
+/// A running xdg-dbus-proxy; dropping it stops the proxy.
+pub struct Proxy {
+    child: Child,
+    /// Read end of the sync pipe; the proxy also exits once it is closed.
+    sync: File,
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Starts `program` for `proxy` as `uid`:`gid`, chrooted to `root`, and
+/// waits until it accepts connections. `old_mask` is the signal mask from
+/// before `child::block_signals`. Rejected calls are reported with `app_id`.
+pub fn start_proxy(
+    program: &str,
+    proxy: &DbusProxy,
+    app_id: &str,
+    root: &Path,
+    uid: u32,
+    gid: u32,
+    old_mask: SigSet,
+) -> Result<Proxy> {
+    let _ = fs::remove_file(root.join(proxy.path.strip_prefix("/").unwrap_or(&proxy.path)));
+    let root = CString::new(root.as_os_str().as_bytes()).map_err(io::Error::from)?;
+    let (sync_read, sync_write) = pipe2(OFlag::O_CLOEXEC).map_err(io::Error::from)?;
+    let sync_fd = sync_write.as_raw_fd();
+    let old_mask: libc::sigset_t = *old_mask.as_ref();
+    let mut cmd = Command::new(program);
+    cmd.arg(format!("--fd={}", sync_fd));
+    cmd.arg(&proxy.address).arg(&proxy.path).args(&proxy.args);
+    if proxy.args.iter().any(|a| a == "--log") {
+        cmd.stdout(Stdio::piped());
+    }
+    unsafe {
+        cmd.pre_exec(move || {
+            libc::fcntl(sync_fd, libc::F_SETFD, 0);
+            libc::sigprocmask(libc::SIG_SETMASK, &old_mask, std::ptr::null_mut());
+            // Command::uid would drop root before the chroot, so both are
+            // done here.
+            let gid = gid as libc::gid_t;
+            if libc::chroot(root.as_ptr()) != 0
+                || libc::chdir(c"/".as_ptr()) != 0
+                || libc::setgroups(1, &gid) != 0
+                || libc::setgid(gid) != 0
+                || libc::setuid(uid as libc::uid_t) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = cmd.spawn().map_err(|e| Error::spawn(program, e))?;
+    drop(sync_write);
+    if let Some(out) = child.stdout.take() {
+        let label = format!("{} ({} bus)", app_id, proxy.bus);
+        std::thread::spawn(move || log_rejections(BufReader::new(out), &label, io::stderr()));
+    }
+    let mut running = Proxy { child, sync: File::from(sync_read) };
+    // The proxy writes one byte once it listens, or exits and closes the pipe.
+    let mut ready = [0u8; 1];
+    match running.sync.read(&mut ready) {
+        Ok(1) => Ok(running),
+        _ => Err(Error::spawn(program, io::Error::other(format!("{} bus proxy exited before it was ready", proxy.bus)))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn socket_path_takes_the_first_unix_path() {
+        assert_eq!(socket_path("unix:path=/var/run/user/1001/bus"), Some(PathBuf::from("/var/run/user/1001/bus")));
+        assert_eq!(socket_path("unix:guid=abc,path=/tmp/bus"), Some(PathBuf::from("/tmp/bus")));
+        assert_eq!(socket_path("tcp:host=localhost,port=1234;unix:path=/tmp/b1;unix:path=/tmp/b2"), Some(PathBuf::from("/tmp/b1")));
+        assert_eq!(socket_path("unix:abstract=/tmp/dbus-XYZ"), None);
+        assert_eq!(socket_path("tcp:host=localhost,port=1234"), None);
+        assert_eq!(socket_path(""), None);
+    }
+
+    #[test]
+    fn policy_args_skip_unknown_levels() {
+        let policy = policy(&[("org.a", "talk"), ("org.b", "own"), ("org.c", "see"), ("org.d", "none"), ("org.e", "bogus")]);
+        assert_eq!(policy_args(&policy).collect::<Vec<_>>(), ["--talk=org.a", "--own=org.b", "--see=org.c"]);
+    }
+
+    #[test]
+    fn session_filter_allows_own_names_and_portals() {
+        let args = session_filter("org.test.App", &policy(&[("org.freedesktop.Notifications", "talk")]));
+        assert_eq!(
+            args,
+            [
+                "--filter",
+                "--log",
+                "--own=org.test.App.*",
+                "--own=org.mpris.MediaPlayer2.org.test.App.*",
+                "--talk=org.freedesktop.portal.*",
+                "--call=org.freedesktop.portal.*=*",
+                "--broadcast=org.freedesktop.portal.*=@/org/freedesktop/portal/*",
+                "--talk=org.freedesktop.Notifications",
+            ]
+        );
+    }
+
+    #[test]
+    fn system_filter_grants_only_the_policy() {
+        assert_eq!(system_filter(&BTreeMap::new()), ["--filter", "--log"]);
+        assert_eq!(system_filter(&policy(&[("org.freedesktop.UPower", "talk")])), ["--filter", "--log", "--talk=org.freedesktop.UPower"]);
+    }
+
+    #[test]
+    fn rejections_pair_verdicts_with_their_messages() {
+        let log = "C1: -> org.freedesktop.DBus call org.freedesktop.DBus.Hello at /org/freedesktop/DBus\n\
+                   C2: -> org.gnome.Shell call org.gnome.Shell.Eval at /org/gnome/Shell\n\
+                   *DENIED* (ping)\n\
+                   C3: -> org.freedesktop.DBus call org.freedesktop.DBus.GetNameOwner at /\n\
+                   *HIDDEN* (ping)\n\
+                   *SKIPPED*\n";
+        let found: Vec<_> = rejections(log.as_bytes()).collect();
+        assert_eq!(
+            found,
+            [
+                ("C2: -> org.gnome.Shell call org.gnome.Shell.Eval at /org/gnome/Shell".to_string(), "*DENIED* (ping)".to_string()),
+                ("C3: -> org.freedesktop.DBus call org.freedesktop.DBus.GetNameOwner at /".to_string(), "*HIDDEN* (ping)".to_string()),
+            ]
+        );
+        let mut reported = Vec::new();
+        log_rejections(log.as_bytes(), "org.test.App (session bus)", &mut reported);
+        assert_eq!(
+            String::from_utf8(reported).unwrap(),
+            "🚫 org.test.App (session bus): rejected C2: -> org.gnome.Shell call org.gnome.Shell.Eval at /org/gnome/Shell *DENIED* (ping)\n\
+             🚫 org.test.App (session bus): rejected C3: -> org.freedesktop.DBus call org.freedesktop.DBus.GetNameOwner at / *HIDDEN* (ping)\n"
+        );
+    }
+
+    #[test]
+    fn filters_for_a_mixed_policy() {
+        let policy = policy(&[
+            ("org.freedesktop.Notifications", "talk"),
+            ("org.freedesktop.secrets", "none"),
+            ("org.kde.StatusNotifierWatcher", "see"),
+            ("org.test.App.Helper", "own"),
+        ]);
+        let session = session_filter("org.test.App", &policy);
+        assert_eq!(
+            session[7..],
+            ["--talk=org.freedesktop.Notifications", "--see=org.kde.StatusNotifierWatcher", "--own=org.test.App.Helper"]
+        );
+        assert!(!session.iter().any(|a| a.contains("org.freedesktop.secrets")));
+        assert_eq!(
+            system_filter(&policy),
+            [
+                "--filter",
+                "--log",
+                "--talk=org.freedesktop.Notifications",
+                "--see=org.kde.StatusNotifierWatcher",
+                "--own=org.test.App.Helper",
+            ]
+        );
+    }
+
+    #[test]
+    fn bus_addresses_come_from_the_host() {
+        let root = crate::elf::fixture::scratch_dir("dbus");
+        let mut host = Host { instance_id: String::new(), env: BTreeMap::new(), root: root.clone(), user: None };
+        assert_eq!(session_bus_address(&host, 1001), None);
+        assert_eq!(system_bus_address(&host), None);
+        assert!(!has_service(&host, "org.freedesktop.portal.Desktop"));
+
+        fs::create_dir_all(root.join("var/run/user/1001")).unwrap();
+        fs::write(root.join("var/run/user/1001/bus"), "").unwrap();
+        fs::create_dir_all(root.join("usr/local/share/dbus-1/services")).unwrap();
+        fs::write(root.join("usr/local/share/dbus-1/services/org.freedesktop.portal.Desktop.service"), "").unwrap();
+        assert_eq!(session_bus_address(&host, 1001).as_deref(), Some("unix:path=/var/run/user/1001/bus"));
+        assert!(has_service(&host, "org.freedesktop.portal.Desktop"));
+
+        host.env.insert("DBUS_SESSION_BUS_ADDRESS".to_string(), "unix:path=/tmp/session".to_string());
+        host.env.insert("DBUS_SYSTEM_BUS_ADDRESS".to_string(), "unix:path=/tmp/system".to_string());
+        assert_eq!(session_bus_address(&host, 1001).as_deref(), Some("unix:path=/tmp/session"));
+        assert_eq!(system_bus_address(&host).as_deref(), Some("unix:path=/tmp/system"));
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::config::{glob_match, InjectProfile};
+use crate::elf::{self, ElfInfo};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Directories the dynamic linker falls back to after the configured paths.
+const DEFAULT_LIB_DIRS: &[&str] = &["/lib", "/usr/lib", "/lib64", "/usr/lib64"];
+
+/// Outcome of walking the DT_NEEDED graph of one or more entry objects.
+/// All paths are as seen from inside the jail.
+#[derive(Debug, Default)]
+pub struct DependencyReport {
+    /// soname -> path it resolved to
+    pub resolved: BTreeMap<String, PathBuf>,
+    /// soname -> objects that need it
+    pub missing: BTreeMap<String, BTreeSet<PathBuf>>,
+    /// Entry objects that could not be parsed as ELF
+    pub unreadable: Vec<PathBuf>,
+}
+
+/// Resolves `path` (absolute, inside the jail) to a host path under `root`,
+/// following symlinks as if `root` were `/`.
+pub fn resolve_in_root(root: &Path, path: &Path) -> Option<PathBuf> {
+    let mut pending: VecDeque<PathBuf> = path.components().map(|c| PathBuf::from(c.as_os_str())).collect();
+    let mut current = PathBuf::from("/");
+    let mut links = 0;
+    while let Some(part) = pending.pop_front() {
+        match part.components().next()? {
+            Component::RootDir => current = PathBuf::from("/"),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                current.pop();
+            }
+            Component::Normal(name) => {
+                let candidate = current.join(name);
+                let host = root.join(candidate.strip_prefix("/").ok()?);
+                match fs::read_link(&host) {
+                    Ok(target) => {
+                        links += 1;
+                        if links > 40 {
+                            return None;
+                        }
+                        for (i, c) in target.components().enumerate() {
+                            pending.insert(i, PathBuf::from(c.as_os_str()));
+                        }
+                    }
+                    Err(_) => current = candidate,
+                }
+            }
+            Component::Prefix(_) => return None,
+        }
+    }
+    let host = root.join(current.strip_prefix("/").ok()?);
+    host.exists().then_some(host)
+}
+
+fn expand_origin(dir: &str, origin: &Path) -> String {
+    let origin = origin.to_string_lossy();
+    dir.replace("${ORIGIN}", &origin).replace("$ORIGIN", &origin)
+}
+
+/// Library search order of glibc's ld.so for `obj` located at `obj_path`:
+/// DT_RPATH (only without DT_RUNPATH), LD_LIBRARY_PATH, DT_RUNPATH, defaults.
+fn search_dirs(obj: &ElfInfo, obj_path: &Path, ld_library_path: &[&str]) -> Vec<String> {
+    let origin = obj_path.parent().unwrap_or(Path::new("/"));
+    let mut dirs = Vec::new();
+    if obj.runpath.is_empty() {
+        dirs.extend(obj.rpath.iter().map(|d| expand_origin(d, origin)));
+    }
+    dirs.extend(ld_library_path.iter().map(|d| d.to_string()));
+    dirs.extend(obj.runpath.iter().map(|d| expand_origin(d, origin)));
+    dirs.extend(DEFAULT_LIB_DIRS.iter().map(|d| d.to_string()));
+    dirs
+}
+
+/// Walks the dependency graph of `entries` inside the jail at `root`.
+pub fn resolve(root: &Path, entries: &[PathBuf], ld_library_path: &[&str]) -> DependencyReport {
+    let mut report = DependencyReport::default();
+    let mut queue: VecDeque<(PathBuf, ElfInfo)> = VecDeque::new();
+    let mut seen: BTreeSet<PathBuf> = BTreeSet::new();
+    for entry in entries {
+        match resolve_in_root(root, entry).map(|p| elf::parse_file(&p)) {
+            Some(Ok(info)) => {
+                seen.insert(entry.clone());
+                queue.push_back((entry.clone(), info));
+            }
+            _ => report.unreadable.push(entry.clone()),
+        }
+    }
+    while let Some((obj_path, obj)) = queue.pop_front() {
+        for soname in &obj.needed {
+            if report.resolved.contains_key(soname) {
+                continue;
+            }
+            let found = search_dirs(&obj, &obj_path, ld_library_path).iter().find_map(|dir| {
+                let jail_path = Path::new(dir).join(soname);
+                let host_path = resolve_in_root(root, &jail_path)?;
+                let info = elf::parse_file(&host_path).ok()?;
+                obj.compatible_with(&info).then_some((jail_path, info))
+            });
+            match found {
+                Some((jail_path, info)) => {
+                    report.missing.remove(soname);
+                    report.resolved.insert(soname.clone(), jail_path.clone());
+                    if seen.insert(jail_path.clone()) {
+                        queue.push_back((jail_path, info));
+                    }
+                }
+                None => {
+                    report.missing.entry(soname.clone()).or_default().insert(obj_path.clone());
+                }
+            }
+        }
+    }
+    report
+}
+
+fn copy_into_jail(root: &Path, host_path: &Path, target: &str) -> bool {
+    let target_dir = root.join(target.trim_start_matches('/'));
+    let _ = fs::create_dir_all(&target_dir);
+    let target_file = target_dir.join(host_path.file_name().unwrap_or_default());
+    match fs::copy(host_path, &target_file) {
+        Ok(_) => {
+            println!("✅ Injected {} into jail: {}", host_path.display(), target_file.display());
+            true
+        }
+        Err(e) => {
+            eprintln!("❌ Failed to copy {} -> {}: {}", host_path.display(), target_file.display(), e);
+            false
+        }
+    }
+}
+
+/// Copies every library matching the profile's globs into its target directory.
+pub fn inject_profile(root: &Path, profile: &InjectProfile) {
+    for dir in &profile.roots {
+        let Ok(entries) = fs::read_dir(dir) else { continue };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if profile.libs.iter().any(|g| glob_match(g, &name)) {
+                let target_file = root.join(profile.target.trim_start_matches('/')).join(&name);
+                if !target_file.exists() {
+                    copy_into_jail(root, &entry.path(), &profile.target);
+                }
+            }
+        }
+    }
+}
+
+/// Copies missing libraries from the profiles' roots into their target
+/// directories and re-resolves until nothing more can be injected.
+pub fn inject_missing(
+    root: &Path,
+    entries: &[PathBuf],
+    ld_library_path: &[&str],
+    profiles: &[InjectProfile],
+) -> DependencyReport {
+    let mut attempted: BTreeSet<String> = BTreeSet::new();
+    loop {
+        let report = resolve(root, entries, ld_library_path);
+        let mut injected = false;
+        for lib_name in report.missing.keys() {
+            if !attempted.insert(lib_name.clone()) {
+                continue;
+            }
+            let source = profiles.iter().find_map(|p| {
+                p.roots.iter().map(|d| Path::new(d).join(lib_name)).find(|h| h.exists()).map(|h| (h, p))
+            });
+            if let Some((host_path, profile)) = source {
+                injected |= copy_into_jail(root, &host_path, &profile.target);
+            }
+        }
+        if !injected {
+            return report;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::fixture::{scratch_dir, Fixture};
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn resolves_symlinks_inside_the_root() {
+        let root = scratch_dir("deps-symlinks");
+        Fixture::library(&[]).write(&root.join("usr/lib/libfoo.so.1"));
+        symlink("libfoo.so.1", root.join("usr/lib/libfoo.so")).unwrap();
+        symlink("usr/lib", root.join("lib")).unwrap();
+        symlink("/usr/lib", root.join("abs")).unwrap();
+        symlink("../../../../usr/lib/libfoo.so", root.join("usr/lib/up")).unwrap();
+        let expected = Some(root.join("usr/lib/libfoo.so.1"));
+        assert_eq!(resolve_in_root(&root, Path::new("/lib/libfoo.so")), expected);
+        assert_eq!(resolve_in_root(&root, Path::new("/abs/libfoo.so")), expected);
+        assert_eq!(resolve_in_root(&root, Path::new("/usr/lib/up")), expected);
+        assert_eq!(resolve_in_root(&root, Path::new("/lib/libnone.so")), None);
+        symlink("loop", root.join("loop")).unwrap();
+        assert_eq!(resolve_in_root(&root, Path::new("/loop")), None);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn rpath_precedes_ld_library_path_unless_runpath_is_set() {
+        let obj_path = Path::new("/app/bin/app");
+        let rpath_only = ElfInfo { rpath: vec!["$ORIGIN/../lib".into(), "/opt/lib".into()], ..ElfInfo::default() };
+        let dirs = search_dirs(&rpath_only, obj_path, &["/ld"]);
+        assert_eq!(dirs[..3], ["/app/bin/../lib", "/opt/lib", "/ld"]);
+        assert_eq!(dirs[3..], *DEFAULT_LIB_DIRS);
+
+        let both = ElfInfo { runpath: vec!["${ORIGIN}/run".into()], ..rpath_only };
+        let dirs = search_dirs(&both, obj_path, &["/ld"]);
+        assert_eq!(dirs[..2], ["/ld", "/app/bin/run"]);
+        assert_eq!(dirs[2..], *DEFAULT_LIB_DIRS);
+    }
+
+    /// A jail with `/app/bin/app` needing `libfoo.so.1` (through its RUNPATH)
+    /// and `libmissing.so`; libfoo needs `libbar.so.2`, which exists as a
+    /// 32-bit copy on LD_LIBRARY_PATH and a 64-bit one in `/usr/lib`.
+    fn jail(name: &str) -> PathBuf {
+        let root = scratch_dir(name);
+        let app = Fixture { runpath: Some("$ORIGIN/../lib"), needed: vec!["libfoo.so.1", "libmissing.so"], ..Fixture::default() };
+        app.write(&root.join("app/bin/app"));
+        Fixture::library(&["libbar.so.2"]).write(&root.join("app/lib/libfoo.so.1"));
+        Fixture { is_64: false, ..Fixture::library(&[]) }.write(&root.join("ld/libbar.so.2"));
+        Fixture::library(&[]).write(&root.join("usr/lib/libbar.so.2"));
+        root
+    }
+
+    #[test]
+    fn reports_resolved_and_missing_libraries() {
+        let root = jail("deps-resolve");
+        let report = resolve(&root, &[PathBuf::from("/app/bin/app"), PathBuf::from("/app/bin/none")], &["/ld"]);
+        assert_eq!(report.resolved["libfoo.so.1"], Path::new("/app/bin/../lib/libfoo.so.1"));
+        assert_eq!(report.resolved["libbar.so.2"], Path::new("/usr/lib/libbar.so.2"));
+        assert_eq!(report.missing.keys().collect::<Vec<_>>(), ["libmissing.so"]);
+        assert!(report.missing["libmissing.so"].contains(Path::new("/app/bin/app")));
+        assert_eq!(report.unreadable, [PathBuf::from("/app/bin/none")]);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn injects_missing_libraries_from_profiles() {
+        let root = jail("deps-inject");
+        let host = scratch_dir("deps-inject-host");
+        Fixture::library(&["libdeep.so"]).write(&host.join("libmissing.so"));
+        let profile = InjectProfile {
+            name: "test".to_string(),
+            roots: vec![host.to_string_lossy().to_string()],
+            libs: Vec::new(),
+            target: "lib/injected".to_string(),
+        };
+        let report = inject_missing(&root, &[PathBuf::from("/app/bin/app")], &["/lib/injected"], &[profile]);
+        assert!(root.join("lib/injected/libmissing.so").exists());
+        assert_eq!(report.resolved["libmissing.so"], Path::new("/lib/injected/libmissing.so"));
+        // What the injected library needs in turn is reported, not invented.
+        assert_eq!(report.missing.keys().collect::<Vec<_>>(), ["libdeep.so"]);
+        let _ = fs::remove_dir_all(&root);
+        let _ = fs::remove_dir_all(&host);
+    }
+}
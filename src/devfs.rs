@@ -0,0 +1,162 @@
+use crate::error::{Error, Result};
+use crate::permissions::Permissions;
+use std::path::Path;
+use std::process::Command;
+
+/// Nodes every app gets, whatever its `devices=` permissions.
+const BASE_NODES: &[&str] = &["null", "zero", "random", "urandom", "tty", "pts", "pts/*"];
+
+/// Builds the devfs rules for an app's `devices=` permissions, in the order
+/// they have to be applied. An empty list leaves the whole devfs visible.
+pub fn ruleset(perms: &Permissions) -> Vec<String> {
+    if perms.has_device("all") {
+        return Vec::new();
+    }
+    let mut nodes: Vec<&str> = BASE_NODES.to_vec();
+    if perms.has_device("dri") {
+        nodes.extend(["dri", "dri/*", "drm", "drm/*"]);
+    }
+    if perms.has_device("input") {
+        nodes.extend(["input", "input/*"]);
+    }
+    // devices=oss is ours: OSS audio for Linux apps that use it through the Linuxulator.
+    if perms.has_device("oss") {
+        nodes.extend(["dsp*", "mixer*", "sndstat"]);
+    }
+    // devices=kvm has no FreeBSD equivalent (bhyve is not reachable from a jail).
+    let mut rules = vec!["hide".to_string()];
+    rules.extend(nodes.iter().map(|n| format!("path {} unhide", n)));
+    rules
+}
+
+/// First ruleset number flatvodka uses; lower ones are left to
+/// `/etc/devfs.rules`.
+const RULESET_BASE: u32 = 1000;
+
+/// The devfs ruleset of instance `instance_id`.
+pub fn ruleset_number(instance_id: &str) -> u16 {
+    let hash = instance_id.bytes().fold(0u32, |h, b| h.wrapping_mul(31).wrapping_add(u32::from(b)));
+    (RULESET_BASE + hash % (u32::from(u16::MAX) - RULESET_BASE)) as u16
+}
+
+/// `devfs` arguments that load `rules` into ruleset `number`, make it the
+/// ruleset of the devfs at `dev_mount` so that nodes created later follow
+/// it too, and apply it to the nodes already there.
+fn commands(dev_mount: &Path, number: u16, rules: &[String]) -> Vec<Vec<String>> {
+    let set = number.to_string();
+    let mount = dev_mount.to_string_lossy().to_string();
+    let mut commands: Vec<Vec<String>> = rules
+    .iter()
+    .map(|rule| ["rule", "-s", &set, "add"].into_iter().map(str::to_string).chain(rule.split_whitespace().map(str::to_string)).collect())
+    .collect();
+    commands.push(vec!["-m".to_string(), mount.clone(), "ruleset".to_string(), set]);
+    commands.push(vec!["-m".to_string(), mount, "rule".to_string(), "applyset".to_string()]);
+    commands
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let status = Command::new("devfs").args(args).status().map_err(|e| Error::spawn("devfs", e))?;
+    if !status.success() {
+        return Err(Error::Jail(format!("devfs {} failed ({})", args.join(" "), status)));
+    }
+    Ok(())
+}
+
+/// Loads `rules` into ruleset `number` and puts the devfs at `dev_mount`
+/// under it. A ruleset that already exists belongs to someone else.
+pub fn apply(dev_mount: &Path, number: u16, rules: &[String]) -> Result<()> {
+    let sets = Command::new("devfs").args(["rule", "showsets"]).output().map_err(|e| Error::spawn("devfs", e))?;
+    if String::from_utf8_lossy(&sets.stdout).lines().any(|l| l.trim() == number.to_string()) {
+        return Err(Error::Jail(format!("devfs ruleset {} is already in use", number)));
+    }
+    commands(dev_mount, number, rules).iter().try_for_each(|args| run(args))
+}
+
+/// Deletes ruleset `number`.
+pub fn remove(number: u16) {
+    let _ = Command::new("devfs").args(["rule", "-s", &number.to_string(), "delset"]).output();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ini::Ini;
+
+    fn perms(devices: &str) -> Permissions {
+        let metadata = Ini::load_from_str(&format!("[Context]\ndevices={}\n", devices)).unwrap();
+        Permissions::load(&metadata, &[], "org.test.App")
+    }
+
+    fn unhidden(rules: &[String]) -> Vec<&str> {
+        rules.iter().filter_map(|r| r.strip_prefix("path ")?.strip_suffix(" unhide")).collect()
+    }
+
+    #[test]
+    fn default_hides_all_but_the_base_nodes() {
+        let rules = ruleset(&perms(""));
+        assert_eq!(rules[0], "hide");
+        assert_eq!(unhidden(&rules), BASE_NODES);
+        assert_eq!(rules.len(), BASE_NODES.len() + 1);
+    }
+
+    #[test]
+    fn dri_unhides_the_gpu_nodes() {
+        let rules = ruleset(&perms("dri"));
+        let nodes = unhidden(&rules);
+        for node in ["dri", "dri/*", "drm", "drm/*"] {
+            assert!(nodes.contains(&node), "{} missing", node);
+        }
+        assert!(!nodes.contains(&"input"));
+    }
+
+    #[test]
+    fn input_unhides_the_input_nodes() {
+        let rules = ruleset(&perms("input"));
+        let nodes = unhidden(&rules);
+        assert!(nodes.contains(&"input") && nodes.contains(&"input/*"));
+        assert!(!nodes.contains(&"dri"));
+    }
+
+    #[test]
+    fn kvm_adds_nothing() {
+        assert_eq!(ruleset(&perms("kvm")), ruleset(&perms("")));
+    }
+
+    #[test]
+    fn all_leaves_devfs_unrestricted() {
+        assert!(ruleset(&perms("all")).is_empty());
+        assert!(ruleset(&perms("dri;all;input")).is_empty());
+    }
+
+    #[test]
+    fn rules_become_the_ruleset_of_the_mount() {
+        let rules = ruleset(&perms("dri"));
+        let commands = commands(Path::new("/mnt/jail/dev"), 4242, &rules);
+        let lines: Vec<String> = commands.iter().map(|c| c.join(" ")).collect();
+        assert_eq!(lines[0], "rule -s 4242 add hide");
+        assert_eq!(lines[1], "rule -s 4242 add path null unhide");
+        assert!(lines.contains(&"rule -s 4242 add path dri/* unhide".to_string()));
+        assert_eq!(lines.len(), rules.len() + 2);
+        assert_eq!(lines[rules.len()..], ["-m /mnt/jail/dev ruleset 4242", "-m /mnt/jail/dev rule applyset"]);
+        // Globs stay single arguments.
+        assert!(commands.contains(&["rule", "-s", "4242", "add", "path", "pts/*", "unhide"].map(String::from).to_vec()));
+    }
+
+    #[test]
+    fn ruleset_numbers_stay_clear_of_the_system_ones() {
+        for id in ["0", "1234", "4294967295", "not-a-number", ""] {
+            assert!(u32::from(ruleset_number(id)) >= RULESET_BASE, "{}", id);
+        }
+        assert_eq!(ruleset_number("1234"), ruleset_number("1234"));
+        assert_ne!(ruleset_number("1234"), ruleset_number("1235"));
+    }
+
+    #[test]
+    fn oss_unhides_the_audio_nodes() {
+        let rules = ruleset(&perms("oss"));
+        let nodes = unhidden(&rules);
+        for node in ["dsp*", "mixer*", "sndstat"] {
+            assert!(nodes.contains(&node), "{} missing", node);
+        }
+    }
+}
@@ -0,0 +1,422 @@
+use std::fs;
+use std::os::unix::fs::{FileExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+#[cfg(test)]
+pub(crate) mod fixture;
+
+const ELFOSABI_LINUX: u8 = 3;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+const EI_OSABI: usize = 7;
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ELFDATA2MSB: u8 = 2;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+
+/// The parts of an ELF object flatvodka cares about.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ElfInfo {
+    pub is_64: bool,
+    pub little_endian: bool,
+    pub osabi: u8,
+    pub e_type: u16,
+    pub machine: u16,
+    pub interp: Option<String>,
+    pub needed: Vec<String>,
+    pub rpath: Vec<String>,
+    pub runpath: Vec<String>,
+}
+
+impl ElfInfo {
+    /// Whether a library with `other`'s class and machine can be loaded into us.
+    pub fn compatible_with(&self, other: &ElfInfo) -> bool {
+        self.is_64 == other.is_64 && self.machine == other.machine
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    is_64: bool,
+    le: bool,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&self, off: usize) -> Result<[u8; N], String> {
+        off.checked_add(N)
+        .and_then(|end| self.data.get(off..end))
+        .and_then(|s| s.try_into().ok())
+        .ok_or_else(|| format!("truncated ELF at offset {:#x}", off))
+    }
+
+    fn u16(&self, off: usize) -> Result<u16, String> {
+        let b = self.bytes::<2>(off)?;
+        Ok(if self.le { u16::from_le_bytes(b) } else { u16::from_be_bytes(b) })
+    }
+
+    fn u32(&self, off: usize) -> Result<u32, String> {
+        let b = self.bytes::<4>(off)?;
+        Ok(if self.le { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) })
+    }
+
+    fn u64(&self, off: usize) -> Result<u64, String> {
+        let b = self.bytes::<8>(off)?;
+        Ok(if self.le { u64::from_le_bytes(b) } else { u64::from_be_bytes(b) })
+    }
+
+    /// A native-width word: u64 for ELF64, u32 for ELF32.
+    fn word(&self, off: usize) -> Result<u64, String> {
+        if self.is_64 {
+            self.u64(off)
+        } else {
+            self.u32(off).map(u64::from)
+        }
+    }
+
+    fn cstr(&self, off: usize) -> Result<String, String> {
+        let tail = self.data.get(off..).ok_or_else(|| format!("string offset {:#x} out of range", off))?;
+        let end = tail.iter().position(|&b| b == 0).ok_or("unterminated string")?;
+        Ok(String::from_utf8_lossy(&tail[..end]).into_owned())
+    }
+}
+
+struct ProgramHeader {
+    p_type: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+}
+
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(b"\x7fELF")
+}
+
+pub fn parse_file(path: &Path) -> Result<ElfInfo, String> {
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse(&data)
+}
+
+/// Class, byte order and program header table location of an ELF file.
+struct Layout {
+    is_64: bool,
+    le: bool,
+    phoff: u64,
+    phentsize: u16,
+    phnum: u16,
+}
+
+impl Layout {
+    fn read(data: &[u8]) -> Result<Self, String> {
+        if !is_elf(data) || data.len() < 16 {
+            return Err("not an ELF file".to_string());
+        }
+        let is_64 = match data[EI_CLASS] {
+            ELFCLASS32 => false,
+            ELFCLASS64 => true,
+            c => return Err(format!("unknown ELF class {}", c)),
+        };
+        let le = match data[EI_DATA] {
+            ELFDATA2LSB => true,
+            ELFDATA2MSB => false,
+            d => return Err(format!("unknown ELF data encoding {}", d)),
+        };
+        let r = Reader { data, is_64, le };
+        let (phoff, phentsize, phnum) = if is_64 {
+            (r.u64(32)?, r.u16(54)?, r.u16(56)?)
+        } else {
+            (u64::from(r.u32(28)?), r.u16(42)?, r.u16(44)?)
+        };
+        Ok(Layout { is_64, le, phoff, phentsize, phnum })
+    }
+
+    /// Bytes from the start of the file to the end of the program headers.
+    fn headers_end(&self) -> Option<u64> {
+        self.phoff.checked_add(u64::from(self.phentsize) * u64::from(self.phnum))
+    }
+}
+
+/// The ELF header and program headers at the start of `data`.
+fn parse_headers(data: &[u8]) -> Result<(ElfInfo, Vec<ProgramHeader>), String> {
+    let Layout { is_64, le, phoff, phentsize, phnum } = Layout::read(data)?;
+    let r = Reader { data, is_64, le };
+    let info = ElfInfo {
+        is_64,
+        little_endian: le,
+        osabi: data[EI_OSABI],
+        e_type: r.u16(16)?,
+        machine: r.u16(18)?,
+        ..ElfInfo::default()
+    };
+    let mut headers = Vec::with_capacity(phnum as usize);
+    for i in 0..u64::from(phnum) {
+        let base = phoff.saturating_add(i * u64::from(phentsize)) as usize;
+        let ph = if is_64 {
+            ProgramHeader {
+                p_type: r.u32(base)?,
+                offset: r.u64(base.saturating_add(8))?,
+                vaddr: r.u64(base.saturating_add(16))?,
+                filesz: r.u64(base.saturating_add(32))?,
+            }
+        } else {
+            ProgramHeader {
+                p_type: r.u32(base)?,
+                offset: u64::from(r.u32(base.saturating_add(4))?),
+                vaddr: u64::from(r.u32(base.saturating_add(8))?),
+                filesz: u64::from(r.u32(base.saturating_add(16))?),
+            }
+        };
+        headers.push(ph);
+    }
+    Ok((info, headers))
+}
+
+pub fn parse(data: &[u8]) -> Result<ElfInfo, String> {
+    let (mut info, headers) = parse_headers(data)?;
+    let r = Reader { data, is_64: info.is_64, le: info.little_endian };
+    if let Some(interp) = headers.iter().find(|h| h.p_type == PT_INTERP) {
+        info.interp = Some(r.cstr(interp.offset as usize)?);
+    }
+    let Some(dynamic) = headers.iter().find(|h| h.p_type == PT_DYNAMIC) else {
+        return Ok(info);
+    };
+    let entsize = if info.is_64 { 16 } else { 8 };
+    let mut entries = Vec::new();
+    let mut strtab_vaddr = None;
+    for i in 0..dynamic.filesz / entsize {
+        let off = dynamic.offset.saturating_add(i * entsize) as usize;
+        let tag = r.word(off)?;
+        let val = r.word(off.saturating_add(entsize as usize / 2))?;
+        match tag {
+            DT_NULL => break,
+            DT_STRTAB => strtab_vaddr = Some(val),
+            DT_NEEDED | DT_RPATH | DT_RUNPATH => entries.push((tag, val)),
+            _ => {}
+        }
+    }
+    let Some(strtab_vaddr) = strtab_vaddr else {
+        return Ok(info);
+    };
+    let strtab = headers
+    .iter()
+    .filter(|h| h.p_type == PT_LOAD)
+    .find(|h| strtab_vaddr >= h.vaddr && strtab_vaddr - h.vaddr < h.filesz)
+    .and_then(|h| h.offset.checked_add(strtab_vaddr - h.vaddr))
+    .ok_or("DT_STRTAB is not inside a loadable segment")?;
+    for (tag, val) in entries {
+        let s = r.cstr(strtab.saturating_add(val) as usize)?;
+        match tag {
+            DT_NEEDED => info.needed.push(s),
+            DT_RPATH => info.rpath.extend(s.split(':').filter(|p| !p.is_empty()).map(str::to_string)),
+            _ => info.runpath.extend(s.split(':').filter(|p| !p.is_empty()).map(str::to_string)),
+        }
+    }
+    Ok(info)
+}
+
+/// Reads no more of a file than the ELF header and the program headers;
+/// anything that puts them further in is not worth branding.
+const MAX_HEADERS_LEN: u64 = 64 * 1024;
+
+/// Sets EI_OSABI to ELFOSABI_LINUX on `path` if it is an unbranded ELF
+/// executable, the same as `brandelf -t Linux`. Returns whether it changed.
+/// Only the headers are read, however large the file.
+pub fn brand_linux(path: &Path) -> Result<bool, String> {
+    let err = |e: std::io::Error| format!("{}: {}", path.display(), e);
+    let file = fs::File::open(path).map_err(err)?;
+    let mut head = [0u8; 64];
+    let len = file.read_at(&mut head, 0).map_err(err)?;
+    if !is_elf(&head[..len]) {
+        return Ok(false);
+    }
+    let headers_end = Layout::read(&head[..len])?
+    .headers_end()
+    .filter(|&end| end <= MAX_HEADERS_LEN)
+    .ok_or_else(|| format!("{}: program headers out of range", path.display()))?;
+    let mut data = vec![0u8; headers_end.max(len as u64) as usize];
+    file.read_exact_at(&mut data, 0).map_err(err)?;
+    let (info, headers) = parse_headers(&data)?;
+    // Executables are ET_EXEC objects and PIEs (ET_DYN with an interpreter).
+    let has_interp = headers.iter().any(|h| h.p_type == PT_INTERP);
+    let executable = info.e_type == ET_EXEC || (info.e_type == ET_DYN && has_interp);
+    if !executable || info.osabi == ELFOSABI_LINUX {
+        return Ok(false);
+    }
+    let perms = file.metadata().map_err(err)?.permissions();
+    drop(file);
+    let writable = perms.mode() & 0o200 != 0;
+    if !writable {
+        let _ = fs::set_permissions(path, fs::Permissions::from_mode(perms.mode() | 0o200));
+    }
+    let result = fs::OpenOptions::new()
+    .write(true)
+    .open(path)
+    .and_then(|f| f.write_all_at(&[ELFOSABI_LINUX], EI_OSABI as u64));
+    if !writable {
+        let _ = fs::set_permissions(path, perms);
+    }
+    result.map(|_| true).map_err(err)
+}
+
+/// Brands every ELF executable below `root`. Returns the branded paths
+/// relative to `root`, plus the files that could not be branded.
+pub fn brand_tree(root: &Path) -> (Vec<PathBuf>, Vec<(PathBuf, String)>) {
+    let mut branded = Vec::new();
+    let mut failed = Vec::new();
+    for entry in WalkDir::new(root).into_iter().flatten() {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        match brand_linux(entry.path()) {
+            Ok(true) => branded.push(entry.path().strip_prefix(root).unwrap_or(entry.path()).to_path_buf()),
+            Ok(false) => {}
+            Err(e) => failed.push((entry.path().to_path_buf(), e)),
+        }
+    }
+    (branded, failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixture::Fixture;
+    use super::*;
+
+    #[test]
+    fn parses_needed_and_search_paths() {
+        let fixture = Fixture {
+            interp: Some("/lib64/ld-linux-x86-64.so.2"),
+            needed: vec!["libfoo.so.1", "libc.so.6"],
+            rpath: Some("$ORIGIN/../lib:/opt/lib"),
+            runpath: Some("/run/lib"),
+            ..Fixture::default()
+        };
+        let info = parse(&fixture.build()).unwrap();
+        assert!(info.is_64 && info.little_endian);
+        assert_eq!(info.machine, 62);
+        assert_eq!(info.interp.as_deref(), Some("/lib64/ld-linux-x86-64.so.2"));
+        assert_eq!(info.needed, ["libfoo.so.1", "libc.so.6"]);
+        assert_eq!(info.rpath, ["$ORIGIN/../lib", "/opt/lib"]);
+        assert_eq!(info.runpath, ["/run/lib"]);
+    }
+
+    #[test]
+    fn parses_elf32() {
+        let fixture = Fixture { is_64: false, needed: vec!["libc.so.6"], ..Fixture::default() };
+        let info = parse(&fixture.build()).unwrap();
+        assert!(!info.is_64);
+        assert_eq!(info.machine, 3);
+        assert_eq!(info.needed, ["libc.so.6"]);
+        let other = parse(&Fixture::default().build()).unwrap();
+        assert!(!info.compatible_with(&other));
+    }
+
+    #[test]
+    fn static_object_has_no_dependencies() {
+        let info = parse(&Fixture::default().build()).unwrap();
+        assert!(info.needed.is_empty() && info.interp.is_none());
+    }
+
+    #[test]
+    fn rejects_non_elf_and_truncated_input() {
+        assert!(parse(b"#!/bin/sh\necho hi\n").is_err());
+        let data = Fixture::library(&["libc.so.6"]).build();
+        assert!(parse(&data[..80]).is_err());
+    }
+
+    #[test]
+    fn crafted_offsets_do_not_panic() {
+        let mut data = Fixture::library(&["libc.so.6"]).build();
+        // The PT_LOAD offset, then the program header table offset.
+        data[64 + 8..64 + 16].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(parse(&data).is_err());
+        data[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(parse(&data).is_err());
+    }
+
+    fn brand(name: &str, data: &[u8], mode: u32) -> (bool, Vec<u8>, u32) {
+        let dir = super::fixture::scratch_dir(&format!("brand-{}", name));
+        let path = dir.join("obj");
+        fs::write(&path, data).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        let changed = brand_linux(&path).unwrap();
+        let after = fs::read(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode() & 0o7777;
+        let _ = fs::remove_dir_all(&dir);
+        (changed, after, mode)
+    }
+
+    #[test]
+    fn brands_unbranded_executables() {
+        for is_64 in [true, false] {
+            let data = Fixture { is_64, ..Fixture::default() }.build();
+            let (changed, after, _) = brand(&format!("exec-{}", is_64), &data, 0o755);
+            assert!(changed);
+            assert_eq!(after[EI_OSABI], ELFOSABI_LINUX);
+            assert_eq!(after[..EI_OSABI], data[..EI_OSABI]);
+            assert_eq!(after[EI_OSABI + 1..], data[EI_OSABI + 1..]);
+        }
+    }
+
+    #[test]
+    fn leaves_branded_executables_alone() {
+        let data = Fixture { osabi: ELFOSABI_LINUX, ..Fixture::default() }.build();
+        let (changed, after, _) = brand("branded", &data, 0o755);
+        assert!(!changed);
+        assert_eq!(after, data);
+    }
+
+    #[test]
+    fn brands_pie_but_not_shared_libraries() {
+        let pie = Fixture { e_type: ET_DYN, interp: Some("/lib64/ld-linux-x86-64.so.2"), ..Fixture::default() };
+        let (changed, after, _) = brand("pie", &pie.build(), 0o755);
+        assert!(changed);
+        assert_eq!(after[EI_OSABI], ELFOSABI_LINUX);
+
+        let lib = Fixture::library(&["libc.so.6"]).build();
+        let (changed, after, _) = brand("lib", &lib, 0o644);
+        assert!(!changed);
+        assert_eq!(after, lib);
+    }
+
+    #[test]
+    fn brands_read_only_files_and_restores_their_mode() {
+        let (changed, after, mode) = brand("ro", &Fixture::default().build(), 0o555);
+        assert!(changed);
+        assert_eq!(after[EI_OSABI], ELFOSABI_LINUX);
+        assert_eq!(mode, 0o555);
+    }
+
+    #[test]
+    fn ignores_non_elf_files() {
+        for (name, data) in [("script", &b"#!/bin/sh\nexit 0\n"[..]), ("empty", &b""[..]), ("short", &b"\x7fEL"[..])] {
+            let (changed, after, _) = brand(name, data, 0o755);
+            assert!(!changed);
+            assert_eq!(after, data);
+        }
+    }
+
+    #[test]
+    fn brand_tree_reports_what_it_branded() {
+        let root = super::fixture::scratch_dir("brand-tree");
+        Fixture::default().write(&root.join("bin/app"));
+        Fixture::library(&[]).write(&root.join("lib/libfoo.so"));
+        fs::write(root.join("bin/data.pak"), vec![0u8; 4096]).unwrap();
+        let (branded, failed) = brand_tree(&root);
+        assert_eq!(branded, [PathBuf::from("bin/app")]);
+        assert!(failed.is_empty());
+        let _ = fs::remove_dir_all(&root);
+    }
+}
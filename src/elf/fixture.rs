@@ -0,0 +1,123 @@
+//! Minimal ELF objects for tests: a header, one PT_LOAD covering the whole
+//! file, and optionally PT_INTERP and a PT_DYNAMIC with DT_NEEDED, DT_RPATH
+//! and DT_RUNPATH entries.
+
+use super::{DT_NEEDED, DT_NULL, DT_RPATH, DT_RUNPATH, DT_STRTAB, ET_EXEC, PT_DYNAMIC, PT_INTERP, PT_LOAD};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const VADDR: u64 = 0x400000;
+
+pub struct Fixture {
+    pub is_64: bool,
+    pub osabi: u8,
+    pub e_type: u16,
+    pub interp: Option<&'static str>,
+    pub needed: Vec<&'static str>,
+    pub rpath: Option<&'static str>,
+    pub runpath: Option<&'static str>,
+}
+
+impl Default for Fixture {
+    fn default() -> Self {
+        Fixture { is_64: true, osabi: 0, e_type: ET_EXEC, interp: None, needed: Vec::new(), rpath: None, runpath: None }
+    }
+}
+
+impl Fixture {
+    /// A shared library (ET_DYN without an interpreter) needing `needed`.
+    pub fn library(needed: &[&'static str]) -> Self {
+        Fixture { e_type: super::ET_DYN, needed: needed.to_vec(), ..Fixture::default() }
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let (ehsize, phentsize, word) = if self.is_64 { (64, 56, 8) } else { (52, 32, 4) };
+        let dynamic = !self.needed.is_empty() || self.rpath.is_some() || self.runpath.is_some();
+        let phnum = 1 + usize::from(self.interp.is_some()) + usize::from(dynamic);
+        let data_off = ehsize + phnum * phentsize;
+
+        let mut strtab = vec![0u8];
+        let mut add = |s: &str| {
+            let off = strtab.len() as u64;
+            strtab.extend_from_slice(s.as_bytes());
+            strtab.push(0);
+            off
+        };
+        let mut entries: Vec<(u64, u64)> = self.needed.iter().map(|n| (DT_NEEDED, add(n))).collect();
+        if let Some(rpath) = self.rpath {
+            entries.push((DT_RPATH, add(rpath)));
+        }
+        if let Some(runpath) = self.runpath {
+            entries.push((DT_RUNPATH, add(runpath)));
+        }
+        let interp = self.interp.map(&mut add);
+        entries.push((DT_STRTAB, VADDR + data_off as u64));
+        entries.push((DT_NULL, 0));
+        let dyn_off = (data_off + strtab.len()).next_multiple_of(8);
+        let dyn_size = if dynamic { entries.len() * 2 * word } else { 0 };
+        let total = dyn_off + dyn_size;
+
+        let mut out = vec![0u8; total];
+        // e_ident, then the fields of the header proper.
+        put(&mut out, 0, 0x464c457f, 4);
+        put(&mut out, 4, if self.is_64 { 2 } else { 1 }, 1);
+        put(&mut out, 5, 1, 1);
+        put(&mut out, 6, 1, 1);
+        put(&mut out, 7, u64::from(self.osabi), 1);
+        put(&mut out, 16, u64::from(self.e_type), 2);
+        put(&mut out, 18, if self.is_64 { 62 } else { 3 }, 2);
+        put(&mut out, 20, 1, 4);
+        let (phoff_at, phentsize_at) = if self.is_64 { (32, 54) } else { (28, 42) };
+        put(&mut out, phoff_at, ehsize as u64, word);
+        put(&mut out, phentsize_at - 2, ehsize as u64, 2);
+        put(&mut out, phentsize_at, phentsize as u64, 2);
+        put(&mut out, phentsize_at + 2, phnum as u64, 2);
+
+        let mut headers = vec![(PT_LOAD, 0, VADDR, total as u64)];
+        if let Some(off) = interp {
+            let len = self.interp.unwrap_or_default().len() as u64 + 1;
+            headers.push((PT_INTERP, data_off as u64 + off, VADDR + data_off as u64 + off, len));
+        }
+        if dynamic {
+            headers.push((PT_DYNAMIC, dyn_off as u64, VADDR + dyn_off as u64, dyn_size as u64));
+        }
+        for (i, (p_type, offset, vaddr, size)) in headers.into_iter().enumerate() {
+            let base = ehsize + i * phentsize;
+            put(&mut out, base, u64::from(p_type), 4);
+            if self.is_64 {
+                for (at, value) in [(8, offset), (16, vaddr), (24, vaddr), (32, size), (40, size)] {
+                    put(&mut out, base + at, value, 8);
+                }
+            } else {
+                for (at, value) in [(4, offset), (8, vaddr), (12, vaddr), (16, size), (20, size)] {
+                    put(&mut out, base + at, value, 4);
+                }
+            }
+        }
+        out[data_off..data_off + strtab.len()].copy_from_slice(&strtab);
+        for (i, (tag, value)) in entries.into_iter().enumerate().filter(|_| dynamic) {
+            let base = dyn_off + i * 2 * word;
+            put(&mut out, base, tag, word);
+            put(&mut out, base + word, value, word);
+        }
+        out
+    }
+
+    /// Writes the object to `path`, creating its directory.
+    pub fn write(&self, path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, self.build()).unwrap();
+    }
+}
+
+fn put(out: &mut [u8], off: usize, value: u64, size: usize) {
+    out[off..off + size].copy_from_slice(&value.to_le_bytes()[..size]);
+}
+
+/// An empty scratch directory unique to this test process.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("flatvodka-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,93 @@
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use std::path::Path;
+
+/// Library search path inside the jail, ahead of anything injected.
+pub const JAIL_LD_LIBRARY_PATH: &str = "/app/lib:/app/lib64:/lib/x86_64-linux-gnu:/usr/lib/x86_64-linux-gnu:/lib64:/lib:/usr/lib64:/usr/lib";
+
+const TYPELIB_PATH: &str = "/app/lib/girepository-1.0:/usr/lib/girepository-1.0:/usr/lib/x86_64-linux-gnu/girepository-1.0:/lib/girepository-1.0";
+
+const GST_PLUGIN_SYSTEM_PATH: &str = "/app/lib/gstreamer-1.0:/usr/lib/extensions/gstreamer-1.0:/usr/lib/x86_64-linux-gnu/gstreamer-1.0";
+
+/// Used when the runtime ships no loaders cache at a known location.
+pub const DEFAULT_PIXBUF_LOADERS: &str = "/usr/lib/gdk-pixbuf-2.0/2.10.0/loaders.cache";
+
+/// Where runtimes put the gdk-pixbuf loaders cache, relative to the jail root.
+const PIXBUF_LOADERS_CANDIDATES: &[&str] = &[
+    "usr/lib/x86_64-linux-gnu/gdk-pixbuf-2.0/2.10.0/loaders.cache",
+    "usr/lib/gdk-pixbuf-2.0/2.10.0/loaders.cache",
+    "lib/x86_64-linux-gnu/gdk-pixbuf-2.0/2.10.0/loaders.cache",
+    "lib/gdk-pixbuf-2.0/2.10.0/loaders.cache",
+];
+
+/// Environment variables of the app, kept in the order they were set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Environment {
+    vars: Vec<(String, String)>,
+}
+
+impl Environment {
+    /// Sets `key`, replacing an earlier value in place.
+    pub fn set(&mut self, key: &str, value: impl Into<String>) {
+        let value = value.into();
+        match self.vars.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.vars.push((key.to_string(), value)),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.vars.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    pub fn vars(&self) -> &[(String, String)] {
+        &self.vars
+    }
+
+    /// `export KEY='value'; ` statements for `/bin/sh`.
+    pub fn to_exports(&self) -> String {
+        self.vars.iter().map(|(k, v)| format!("export {}={}; ", k, shell_quote(v))).collect()
+    }
+}
+
+/// A map from name to value, in the order the variables were set.
+impl Serialize for Environment {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.vars.len()))?;
+        for (key, value) in &self.vars {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+/// Quotes `arg` for `/bin/sh`.
+pub fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// The gdk-pixbuf loaders cache shipped by the runtime copied to `jail_root`.
+pub fn find_pixbuf_loaders(jail_root: &Path) -> Option<String> {
+    PIXBUF_LOADERS_CANDIDATES.iter().find(|c| jail_root.join(c).exists()).map(|c| format!("/{}", c))
+}
+
+/// The base environment every app gets.
+pub fn app_env(app_id: &str, user: &str, home: &str, uid: u32, ld_library_path: &str, loaders_cache: &str) -> Environment {
+    let mut env = Environment::default();
+    env.set("LD_LIBRARY_PATH", ld_library_path);
+    env.set("TERM", "xterm-256color");
+    env.set("container", "flatpak");
+    env.set("FLATPAK_ID", app_id);
+    env.set("HOME", home);
+    env.set("USER", user);
+    env.set("XDG_RUNTIME_DIR", format!("/run/user/{}", uid));
+    env.set("PATH", "/app/bin:/usr/bin:/bin:/sbin:/usr/sbin");
+    env.set("XDG_DATA_DIRS", "/app/share:/usr/share:/share");
+    env.set("XDG_CONFIG_DIRS", "/app/etc/xdg:/etc/xdg");
+    env.set("XDG_CACHE_HOME", format!("{}/.cache", home));
+    env.set("GI_TYPELIB_PATH", TYPELIB_PATH);
+    env.set("GDK_PIXBUF_MODULE_FILE", loaders_cache);
+    env.set("GST_PLUGIN_SYSTEM_PATH", GST_PLUGIN_SYSTEM_PATH);
+    env.set("XDG_CURRENT_DESKTOP", "GNOME");
+    env.set("LANG", "C.UTF-8");
+    env
+}
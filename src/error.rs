@@ -0,0 +1,122 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything that can make a flatvodka command fail.
+#[derive(Debug)]
+pub enum Error {
+    /// The command needs privileges flatvodka does not have.
+    Permission(String),
+    /// An app, runtime or instance that is not there.
+    NotInstalled(String),
+    /// Unreadable or invalid app metadata or `.flatpakref` file.
+    Metadata(String),
+    /// ostree is missing or one of its steps failed.
+    Ostree(String),
+    /// A filesystem could not be mounted into the jail.
+    Mount(String),
+    /// Creating or controlling the jail failed.
+    Jail(String),
+    /// A program could not be started.
+    Spawn { program: String, source: io::Error },
+    /// An invalid configuration value.
+    Config(String),
+    Io(io::Error),
+    /// `source`, annotated with what was being done when it happened.
+    Context { context: String, source: Box<Error> },
+}
+
+impl Error {
+    pub fn spawn(program: impl AsRef<Path>, source: io::Error) -> Self {
+        Error::Spawn { program: program.as_ref().display().to_string(), source }
+    }
+
+    /// Process exit status for this error; a context keeps its cause's code.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) => 1,
+            Error::Permission(_) => 3,
+            Error::NotInstalled(_) => 4,
+            Error::Metadata(_) => 5,
+            Error::Ostree(_) => 6,
+            Error::Mount(_) => 7,
+            Error::Jail(_) => 8,
+            Error::Spawn { .. } => 9,
+            Error::Config(_) => 10,
+            Error::Context { source, .. } => source.exit_code(),
+        }
+    }
+
+    /// The error and its causes, outermost first.
+    pub fn chain(&self) -> impl Iterator<Item = &(dyn std::error::Error + 'static)> {
+        let mut next: Option<&(dyn std::error::Error + 'static)> = Some(self);
+        std::iter::from_fn(move || {
+            let current = next?;
+            next = current.source();
+            Some(current)
+        })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Permission(msg)
+            | Error::NotInstalled(msg)
+            | Error::Metadata(msg)
+            | Error::Ostree(msg)
+            | Error::Mount(msg)
+            | Error::Jail(msg)
+            | Error::Config(msg) => f.write_str(msg),
+            Error::Spawn { program, .. } => write!(f, "could not start {}", program),
+            Error::Io(e) => e.fmt(f),
+            Error::Context { context, .. } => f.write_str(context),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Spawn { source, .. } => Some(source),
+            Error::Io(e) => e.source(),
+            Error::Context { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Adds context to the error of a `Result`.
+pub trait Context<T> {
+    fn context(self, context: impl Into<String>) -> Result<T>;
+    fn with_context<C: Into<String>>(self, f: impl FnOnce() -> C) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for std::result::Result<T, E> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|e| Error::Context { context: context.into(), source: Box::new(e.into()) })
+    }
+
+    fn with_context<C: Into<String>>(self, f: impl FnOnce() -> C) -> Result<T> {
+        self.map_err(|e| Error::Context { context: f().into(), source: Box::new(e.into()) })
+    }
+}
+
+/// Prints `err` and its causes to stderr.
+pub fn report(err: &Error) {
+    let mut chain = err.chain();
+    if let Some(top) = chain.next() {
+        eprintln!("❌ {}", top);
+    }
+    for cause in chain {
+        eprintln!("   caused by: {}", cause);
+    }
+}
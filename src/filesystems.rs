@@ -0,0 +1,202 @@
+use crate::sandbox::HostUser;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Host directories `filesystems=host` exposes, besides the OS (`host-os`,
+/// `host-etc`).
+const HOST_DIRS: &[&str] = &["/home", "/media", "/mnt", "/opt", "/srv"];
+
+/// Jail paths the runtime, app and flatvodka own; nothing is mounted over or
+/// below them.
+const RESERVED: &[&str] = &[
+    "/app", "/bin", "/dev", "/etc", "/lib", "/lib32", "/lib64", "/proc", "/run", "/sbin", "/sys", "/usr", "/.flatpak-info",
+];
+
+/// `xdg-<name>` entries and the `user-dirs.dirs` keys naming them.
+const USER_DIRS: &[(&str, &str)] = &[
+    ("desktop", "XDG_DESKTOP_DIR"),
+    ("documents", "XDG_DOCUMENTS_DIR"),
+    ("download", "XDG_DOWNLOAD_DIR"),
+    ("music", "XDG_MUSIC_DIR"),
+    ("pictures", "XDG_PICTURES_DIR"),
+    ("public-share", "XDG_PUBLICSHARE_DIR"),
+    ("templates", "XDG_TEMPLATES_DIR"),
+    ("videos", "XDG_VIDEOS_DIR"),
+];
+
+/// A host directory mounted into the jail for a `filesystems=` entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Export {
+    pub source: PathBuf,
+    /// Mount point inside the jail; the same path, except that the user's
+    /// home is the jail home.
+    pub target: PathBuf,
+    pub ro: bool,
+    /// Create `source` when it does not exist (`:create`).
+    pub create: bool,
+}
+
+/// A user directory from `~/.config/user-dirs.dirs`. Like GLib, a directory
+/// set to the home itself is disabled and only the desktop has a default.
+fn user_dir(home: &Path, key: &str) -> Option<PathBuf> {
+    let content = fs::read_to_string(home.join(".config/user-dirs.dirs")).unwrap_or_default();
+    let value = content.lines().find_map(|l| l.trim().strip_prefix(key)?.trim_start().strip_prefix('='));
+    let dir = match value {
+        Some(value) => {
+            let value = value.trim().trim_matches('"');
+            match value.strip_prefix("$HOME") {
+                Some(rest) => home.join(rest.trim_start_matches('/')),
+                None => PathBuf::from(value),
+            }
+        }
+        None if key == "XDG_DESKTOP_DIR" => home.join("Desktop"),
+        None => return None,
+    };
+    (dir != home).then_some(dir)
+}
+
+fn join(base: PathBuf, rest: &str) -> PathBuf {
+    if rest.is_empty() {
+        base
+    } else {
+        base.join(rest)
+    }
+}
+
+/// Host path a location (an entry without its `:ro`/`:rw`/`:create` suffix)
+/// stands for, with the jail path it is mounted at. `None` for unknown
+/// locations, `Some(None)` for user directories that are not set.
+fn resolve(location: &str, user: &HostUser) -> Option<Option<(PathBuf, PathBuf)>> {
+    let (base, rest) = location.split_once('/').unwrap_or((location, ""));
+    let source = match base {
+        "home" | "~" => join(user.home.clone(), rest),
+        "xdg-config" => join(user.home.join(".config"), rest),
+        "xdg-cache" => join(user.home.join(".cache"), rest),
+        "xdg-data" => join(user.home.join(".local/share"), rest),
+        "xdg-run" => {
+            let host = join(PathBuf::from(format!("/var/run/user/{}", user.uid)), rest);
+            return Some(Some((host, join(PathBuf::from(format!("/run/user/{}", user.uid)), rest))));
+        }
+        "" => PathBuf::from(location),
+        _ => {
+            let name = base.strip_prefix("xdg-")?;
+            let (_, key) = USER_DIRS.iter().find(|(n, _)| *n == name)?;
+            match user_dir(&user.home, key) {
+                Some(dir) => join(dir, rest),
+                None => return Some(None),
+            }
+        }
+    };
+    let target = match source.strip_prefix(&user.home) {
+        Ok(rel) => join(PathBuf::from(user.jail_home()), &rel.to_string_lossy()),
+        Err(_) => source.clone(),
+    };
+    Some(Some((source, target)))
+}
+
+/// Mounts for the effective `filesystems=` entries, parents before children.
+/// Entries that cannot be honoured are explained in `warnings`; `jail_root`
+/// itself is never exposed, and `:create` only creates inside the home.
+pub fn exports(entries: &[String], user: &HostUser, jail_root: &Path, warnings: &mut Vec<String>) -> Vec<Export> {
+    let mut exports: Vec<Export> = Vec::new();
+    for entry in entries {
+        let (location, mode) = match entry.rsplit_once(':') {
+            Some((loc, m @ ("ro" | "rw" | "create"))) => (loc, m),
+            _ => (entry.as_str(), "rw"),
+        };
+        let ro = mode == "ro";
+        let create = mode == "create";
+        let mut add = |source: PathBuf, target: PathBuf, ro: bool| {
+            exports.retain(|e| e.target != target);
+            exports.push(Export { source, target, ro, create });
+        };
+        match location {
+            "host" => {
+                for dir in HOST_DIRS {
+                    add(PathBuf::from(dir), PathBuf::from(dir), ro);
+                }
+                add(user.home.clone(), PathBuf::from(user.jail_home()), ro);
+                add("/usr".into(), "/run/host/usr".into(), true);
+                add("/etc".into(), "/run/host/etc".into(), true);
+            }
+            // The host OS is only ever visible read-only.
+            "host-os" => add("/usr".into(), "/run/host/usr".into(), true),
+            "host-etc" => add("/etc".into(), "/run/host/etc".into(), true),
+            _ => match resolve(location, user) {
+                Some(Some((source, target))) => {
+                    let reserved = RESERVED.iter().any(|r| target.starts_with(r)) || target == Path::new("/");
+                    let runtime_dir = target.starts_with(format!("/run/user/{}", user.uid));
+                    // `starts_with` compares components, so `..` would slip past both checks.
+                    if source.components().chain(target.components()).any(|c| c == Component::ParentDir) {
+                        warnings.push(format!("filesystems={} contains '..'; ignored", entry));
+                    } else if reserved && !runtime_dir {
+                        warnings.push(format!("filesystems={} would hide {} in the jail; ignored", entry, target.display()));
+                    } else {
+                        add(source, target, ro);
+                    }
+                }
+                Some(None) => {}
+                None => warnings.push(format!("unknown filesystems= entry {}", entry)),
+            },
+        }
+    }
+    // Root creates these, so only where the user could have made them too.
+    for e in exports.iter_mut().filter(|e| e.create && !e.source.starts_with(&user.home)) {
+        warnings.push(format!("{} is outside the home; not creating it", e.source.display()));
+        e.create = false;
+    }
+    exports.retain(|e| {
+        let exposes_jail = jail_root.starts_with(&e.source) || fs::canonicalize(&e.source).is_ok_and(|s| jail_root.starts_with(s));
+        if exposes_jail {
+            warnings.push(format!("{} contains the jail root; not exposed", e.source.display()));
+        }
+        !exposes_jail && (e.create || e.source.exists())
+    });
+    exports.sort_by_key(|e| e.target.components().count());
+    exports
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::fixture::scratch_dir;
+
+    fn user(home: &Path) -> HostUser {
+        HostUser { name: "alice".to_string(), uid: 1001, gid: 1001, home: home.to_path_buf() }
+    }
+
+    fn entries(list: &[&str]) -> Vec<String> {
+        list.iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn create_is_limited_to_the_home() {
+        let root = scratch_dir("filesystems-create");
+        let home = root.join("home/alice");
+        fs::create_dir_all(&home).unwrap();
+        let outside = root.join("srv/data");
+        let list = entries(&["~/Projects:create", &format!("{}:create", outside.display())]);
+        let mut warnings = Vec::new();
+        let exports = exports(&list, &user(&home), Path::new("/mnt/flatvodka_jail"), &mut warnings);
+        assert_eq!(
+            exports,
+            [Export { source: home.join("Projects"), target: "/home/alice/Projects".into(), ro: false, create: true }]
+        );
+        assert_eq!(warnings, [format!("{} is outside the home; not creating it", outside.display())]);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn parent_components_are_rejected() {
+        let root = scratch_dir("filesystems-parent");
+        let home = root.join("home/alice");
+        fs::create_dir_all(home.join(".config")).unwrap();
+        fs::write(home.join(".config/user-dirs.dirs"), "XDG_MUSIC_DIR=\"$HOME/../../../etc\"\n").unwrap();
+        let list = entries(&["~/../../etc", "home/../../../root:create", "/usr/local/../../etc", "xdg-music", "xdg-config/../.ssh"]);
+        let mut warnings = Vec::new();
+        assert_eq!(exports(&list, &user(&home), Path::new("/mnt/flatvodka_jail"), &mut warnings), []);
+        assert_eq!(warnings.len(), list.len());
+        assert!(warnings.iter().all(|w| w.ends_with("contains '..'; ignored")), "{:?}", warnings);
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::permissions::Permissions;
+use ini::{EscapePolicy, Ini};
+use std::fs;
+use std::path::Path;
+
+/// Everything `/.flatpak-info` describes about one running instance.
+pub struct InstanceInfo<'a> {
+    pub app_id: &'a str,
+    pub instance_id: &'a str,
+    pub arch: &'a str,
+    pub branch: &'a str,
+    pub app_commit: &'a str,
+    /// Host path of the app's `files` directory.
+    pub original_app_path: &'a Path,
+    /// `runtime/<id>/<arch>/<branch>`
+    pub runtime_ref: &'a str,
+    pub runtime_commit: &'a str,
+    /// Host path of the runtime's `files` directory.
+    pub runtime_host_path: &'a Path,
+    /// Host directory backing the app's persistent data.
+    pub instance_path: &'a Path,
+    pub flatpak_version: &'a str,
+    /// Whether the session bus is reached through a filtering proxy.
+    pub session_bus_proxy: bool,
+    pub system_bus_proxy: bool,
+    pub perms: &'a Permissions,
+}
+
+/// Escapes `value` the way GLib's keyfile writer does; inside a list, `;`
+/// is escaped too so it does not end the item.
+fn escape(value: &str, in_list: bool) -> String {
+    let mut out = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ' ' if i == 0 => out.push_str("\\s"),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ';' if in_list => out.push_str("\\;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn join(items: impl IntoIterator<Item = impl AsRef<str>>) -> String {
+    items.into_iter().fold(String::new(), |mut acc, item| {
+        acc.push_str(&escape(item.as_ref(), true));
+        acc.push(';');
+        acc
+    })
+}
+
+/// Renders the keyfile in the format GLib and the portals parse. Values are
+/// escaped here, so it is written without rust-ini's own escaping.
+pub fn render(info: &InstanceInfo) -> String {
+    let path = |p: &Path| escape(&p.to_string_lossy(), false);
+    let mut conf = Ini::new();
+    conf.with_section(Some("Application"))
+    .set("name", escape(info.app_id, false))
+    .set("runtime", escape(info.runtime_ref, false));
+    conf.with_section(Some("Instance"))
+    .set("instance-id", escape(info.instance_id, false))
+    .set("instance-path", path(info.instance_path))
+    .set("app-path", "/app")
+    .set("original-app-path", path(info.original_app_path))
+    .set("app-commit", escape(info.app_commit, false))
+    .set("app-extensions", "")
+    .set("arch", escape(info.arch, false))
+    .set("branch", escape(info.branch, false))
+    .set("flatpak-version", escape(info.flatpak_version, false))
+    .set("runtime-path", "/usr")
+    .set("original-runtime-path", path(info.runtime_host_path))
+    .set("runtime-commit", escape(info.runtime_commit, false))
+    .set("runtime-extensions", "")
+    .set("session-bus-proxy", info.session_bus_proxy.to_string())
+    .set("system-bus-proxy", info.system_bus_proxy.to_string());
+    let perms = info.perms;
+    let mut ctx = conf.with_section(Some("Context"));
+    for (key, set) in [
+        ("shared", &perms.shared),
+        ("sockets", &perms.sockets),
+        ("devices", &perms.devices),
+        ("features", &perms.features),
+    ] {
+        if !set.is_empty() {
+            ctx.set(key, join(set));
+        }
+    }
+    let filesystems = perms.effective_filesystems();
+    if !filesystems.is_empty() {
+        ctx.set("filesystems", join(filesystems));
+    }
+    for (section, policy) in [
+        ("Session Bus Policy", &perms.session_bus_policy),
+        ("System Bus Policy", &perms.system_bus_policy),
+    ] {
+        if !policy.is_empty() {
+            let mut sec = conf.with_section(Some(section));
+            for (name, level) in policy {
+                sec.set(name.as_str(), escape(level, false));
+            }
+        }
+    }
+    let mut out = Vec::new();
+    conf.write_to_policy(&mut out, EscapePolicy::Nothing).expect("writing to a Vec cannot fail");
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// A random decimal instance id, like the ones flatpak allocates.
+pub fn new_instance_id() -> String {
+    let mut bytes = [0u8; 4];
+    let random = fs::File::open("/dev/urandom").and_then(|mut f| {
+        use std::io::Read;
+        f.read_exact(&mut bytes)
+    });
+    match random {
+        Ok(()) => u32::from_ne_bytes(bytes).to_string(),
+        Err(_) => std::process::id().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_entries_cannot_split_or_break_lines() {
+        let perms = Permissions {
+            filesystems: vec!["xdg-download".to_string(), "~/My;Files".to_string(), "/tmp/a\nb\\c".to_string()],
+            ..Permissions::default()
+        };
+        let rendered = render(&InstanceInfo {
+            app_id: "org.test.App",
+            instance_id: "1",
+            arch: "x86_64",
+            branch: "stable",
+            app_commit: "abc",
+            original_app_path: Path::new("/var/lib/flatpak/app/org.test.App/files"),
+            runtime_ref: "runtime/org.test.Platform/x86_64/1",
+            runtime_commit: "def",
+            runtime_host_path: Path::new("/var/lib/flatpak/runtime/org.test.Platform/files"),
+            instance_path: Path::new("/home/u/.var/app/org.test.App"),
+            flatpak_version: "1.16.0",
+            session_bus_proxy: true,
+            system_bus_proxy: false,
+            perms: &perms,
+        });
+        let context = rendered.split_once("[Context]").unwrap().1;
+        assert_eq!(context.trim(), r"filesystems=xdg-download;~/My\;Files;/tmp/a\nb\\c;");
+        assert!(rendered.contains("original-app-path=/var/lib/flatpak/app/org.test.App/files\n"));
+        assert!(rendered.contains("session-bus-proxy=true\n"));
+    }
+}
@@ -0,0 +1,184 @@
+use crate::config::VulkanConfig;
+use crate::plan::{Mount, Step};
+use clap::ValueEnum;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Jail-side location of everything Vulkan-related taken from the host.
+const JAIL_VULKAN_DIR: &str = "run/host/vulkan";
+
+/// Which host GPU drivers are made available to the app.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GpuMode {
+    /// No host drivers; software rendering only
+    None,
+    /// Only Mesa Vulkan drivers (libvulkan_*)
+    Mesa,
+    /// Every driver found on the host
+    Auto,
+}
+
+/// A Vulkan ICD or layer manifest found on the host.
+#[derive(Debug)]
+pub struct Manifest {
+    pub json: PathBuf,
+    /// `library_path` as written in the manifest.
+    pub library: String,
+    /// Host file backing `library`, when it could be located.
+    pub host_library: Option<PathBuf>,
+}
+
+impl Manifest {
+    fn load(json: &Path, driver_lib_dirs: &[String]) -> Option<Self> {
+        let content = fs::read_to_string(json).ok()?;
+        let value: serde_json::Value = serde_json::from_str(&content).ok()?;
+        let library = ["ICD", "layer"]
+        .iter()
+        .find_map(|key| value.get(key)?.get("library_path")?.as_str())
+        .or_else(|| value.get("layers")?.get(0)?.get("library_path")?.as_str())?
+        .to_string();
+        let host_library = resolve_library(json, &library, driver_lib_dirs);
+        Some(Manifest { json: json.to_path_buf(), library, host_library })
+    }
+
+    /// Mesa installs its Vulkan drivers as `libvulkan_<driver>.so`.
+    pub fn is_mesa(&self) -> bool {
+        Path::new(&self.library)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with("libvulkan_"))
+    }
+}
+
+/// Resolves a manifest `library_path` the way the Vulkan loader does: paths
+/// with a separator are relative to the manifest, bare names are looked up
+/// in the library search path.
+fn resolve_library(json: &Path, library: &str, driver_lib_dirs: &[String]) -> Option<PathBuf> {
+    let candidate = if library.contains('/') {
+        json.parent()?.join(library)
+    } else {
+        driver_lib_dirs.iter().map(|d| Path::new(d).join(library)).find(|p| p.exists())?
+    };
+    candidate.exists().then_some(candidate)
+}
+
+fn scan(dirs: &[String], driver_lib_dirs: &[String]) -> Vec<Manifest> {
+    let mut manifests = Vec::new();
+    for dir in dirs {
+        if let Ok(entries) = fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                if entry.path().extension().map(|s| s == "json").unwrap_or(false) {
+                    if let Some(m) = Manifest::load(&entry.path(), driver_lib_dirs) {
+                        manifests.push(m);
+                    }
+                }
+            }
+        }
+    }
+    manifests
+}
+
+pub fn discover_icds(mode: GpuMode, config: &VulkanConfig) -> Vec<Manifest> {
+    match mode {
+        GpuMode::None => Vec::new(),
+        GpuMode::Mesa => scan(&config.icd_dirs, &config.driver_lib_dirs).into_iter().filter(Manifest::is_mesa).collect(),
+        GpuMode::Auto => scan(&config.icd_dirs, &config.driver_lib_dirs),
+    }
+}
+
+pub fn discover_layers(mode: GpuMode, config: &VulkanConfig) -> Vec<Manifest> {
+    match mode {
+        GpuMode::None => Vec::new(),
+        _ => scan(&config.layer_dirs, &config.driver_lib_dirs),
+    }
+}
+
+/// Points every `library_path` of a parsed manifest that reads `from` (the
+/// `ICD`, the `layer` or any of the `layers`) at `to`.
+fn set_library_path(value: &mut serde_json::Value, from: &str, to: &str) {
+    let mut objects: Vec<&mut serde_json::Value> = Vec::new();
+    if let Some(obj) = value.as_object_mut() {
+        for (key, entry) in obj.iter_mut() {
+            match (key.as_str(), entry) {
+                ("ICD" | "layer", entry) => objects.push(entry),
+                ("layers", serde_json::Value::Array(layers)) => objects.extend(layers.iter_mut()),
+                _ => {}
+            }
+        }
+    }
+    for object in objects {
+        if let Some(path) = object.get_mut("library_path").filter(|p| p.as_str() == Some(from)) {
+            *path = serde_json::Value::String(to.to_string());
+        }
+    }
+}
+
+/// Environment the Vulkan loader needs to find what `install_steps` placed in the jail.
+#[derive(Debug, Default)]
+pub struct VulkanEnv {
+    pub icd_filenames: Vec<String>,
+    pub layer_path: Option<String>,
+}
+
+/// Steps that place the manifests in the jail under `/run/host/vulkan`,
+/// pointing their `library_path` at nullfs mounts of the host driver libraries.
+pub fn install_steps(icds: &[Manifest], layers: &[Manifest]) -> (Vec<Step>, VulkanEnv) {
+    let base = Path::new("/").join(JAIL_VULKAN_DIR);
+    let lib_dir = base.join("lib");
+    let mut steps = vec![Step::Dir { path: lib_dir.clone(), mode: None, owner: None }];
+    let mut mounted = BTreeSet::new();
+    let mut env = VulkanEnv::default();
+    for (subdir, manifests) in [("icd.d", icds), ("explicit_layer.d", layers)] {
+        let manifest_dir = base.join(subdir);
+        steps.push(Step::Dir { path: manifest_dir.clone(), mode: None, owner: None });
+        for m in manifests {
+            let Some(name) = m.json.file_name() else { continue };
+            let Ok(content) = fs::read_to_string(&m.json) else { continue };
+            let Ok(mut value) = serde_json::from_str::<serde_json::Value>(&content) else { continue };
+            if let Some(host_lib) = &m.host_library {
+                let lib_name = host_lib.file_name().unwrap_or_default().to_string_lossy().to_string();
+                let jail_lib = lib_dir.join(&lib_name);
+                if mounted.insert(lib_name) {
+                    steps.push(Step::Mount(Mount::nullfs(host_lib, &jail_lib, true).file()));
+                }
+                set_library_path(&mut value, &m.library, &jail_lib.to_string_lossy());
+            }
+            let content = serde_json::to_string_pretty(&value).unwrap_or(content);
+            let path = manifest_dir.join(name);
+            if subdir == "icd.d" {
+                env.icd_filenames.push(path.to_string_lossy().to_string());
+            }
+            steps.push(Step::Write { path, content, mode: None });
+        }
+        if subdir == "explicit_layer.d" && !manifests.is_empty() {
+            env.layer_path = Some(manifest_dir.to_string_lossy().to_string());
+        }
+    }
+    (steps, env)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_escaped_library_path() {
+        let content = r#"{"file_format_version": "1.0.0", "ICD": {"library_path": "\/usr\/lib\/libvulkan_radeon.so", "api_version": "1.3.0"}}"#;
+        let mut value: serde_json::Value = serde_json::from_str(content).unwrap();
+        set_library_path(&mut value, "/usr/lib/libvulkan_radeon.so", "/run/host/vulkan/lib/libvulkan_radeon.so");
+        assert_eq!(value["ICD"]["library_path"], "/run/host/vulkan/lib/libvulkan_radeon.so");
+        assert_eq!(value["ICD"]["api_version"], "1.3.0");
+    }
+
+    #[test]
+    fn leaves_other_strings_alone() {
+        let content = r#"{"layer": {"name": "libVkLayer.so", "library_path": "libVkLayer.so"}, "layers": [{"library_path": "libVkLayer.so"}, {"library_path": "other.so"}]}"#;
+        let mut value: serde_json::Value = serde_json::from_str(content).unwrap();
+        set_library_path(&mut value, "libVkLayer.so", "/run/host/vulkan/lib/libVkLayer.so");
+        assert_eq!(value["layer"]["name"], "libVkLayer.so");
+        assert_eq!(value["layer"]["library_path"], "/run/host/vulkan/lib/libVkLayer.so");
+        assert_eq!(value["layers"][0]["library_path"], "/run/host/vulkan/lib/libVkLayer.so");
+        assert_eq!(value["layers"][1]["library_path"], "other.so");
+    }
+}
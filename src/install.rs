@@ -0,0 +1,228 @@
+use crate::config::Config;
+use crate::elf;
+use crate::error::{Context, Error, Result};
+use ini::Ini;
+use nix::unistd::{getuid, User};
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use walkdir::WalkDir;
+
+/// Where an app or runtime is installed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Installation {
+    User,
+    System,
+}
+
+/// Home of the user who invoked flatvodka, looked up in the passwd database:
+/// SUDO_USER when running through sudo, the real uid otherwise, then `$HOME`.
+pub fn invoking_home() -> Result<PathBuf> {
+    let pw = std::env::var("SUDO_USER")
+    .ok()
+    .and_then(|name| User::from_name(&name).ok().flatten())
+    .or_else(|| User::from_uid(getuid()).ok().flatten());
+    match pw {
+        Some(pw) => Ok(pw.dir),
+        None => std::env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+        .ok_or_else(|| Error::Config("cannot find the invoking user's home: no passwd entry and HOME is not set".to_string())),
+    }
+}
+
+pub fn get_flatpak_dir(config: &Config, installation: Installation) -> Result<PathBuf> {
+    match installation {
+        Installation::User => Ok(invoking_home()?.join(&config.user_flatpak_dir)),
+        Installation::System => Ok(PathBuf::from(&config.system_flatpak_dir)),
+    }
+}
+
+pub fn get_repo_dir(config: &Config, installation: Installation) -> Result<PathBuf> {
+    Ok(get_flatpak_dir(config, installation)?.join("repo"))
+}
+
+/// Installations searched when running, in order of preference.
+pub const SEARCH_ORDER: [Installation; 2] = [Installation::User, Installation::System];
+
+/// Finds the active checkout of `kind/name/x86_64/branch` in the first
+/// installation that has it.
+pub fn find_deployment(config: &Config, kind: &str, name: &str, branch: &str, prefer: Option<Installation>) -> Result<Option<(Installation, PathBuf)>> {
+    for inst in prefer.into_iter().chain(SEARCH_ORDER) {
+        let path = get_flatpak_dir(config, inst)?.join(kind).join(name).join("x86_64").join(branch).join("active");
+        if path.join("files").exists() {
+            return Ok(Some((inst, path)));
+        }
+    }
+    Ok(None)
+}
+
+fn find_ostree_binary(config: &Config) -> Option<String> {
+    if let Some(path) = config.ostree_binaries.iter().find(|p| Path::new(p).exists()) {
+        return Some(path.clone());
+    }
+    if Command::new("ostree").arg("--version").output().is_ok() {
+        return Some("ostree".to_string());
+    }
+    None
+}
+
+fn ensure_repo_config_fixed(repo_dir: &Path, config: &Config) {
+    let config_path = repo_dir.join("config");
+
+    let fix_file = |path: &Path| {
+        if path.exists() {
+            if let Ok(mut conf) = Ini::load_from_file(path) {
+                conf.with_section(Some("core")).set("summary-max-size", "268435456");
+                let _ = conf.write_to_file(path);
+            }
+        }
+    };
+
+    fix_file(&config_path);
+
+    let compat_config = PathBuf::from(&config.ostree_compat_root)
+    .join(config_path.strip_prefix("/").unwrap_or(&config_path));
+    if compat_config.exists() && compat_config != config_path {
+        fix_file(&compat_config);
+    }
+}
+
+/// Name of the per-commit record listing the files branded as Linux ELF.
+pub const BRAND_RECORD: &str = ".flatvodka-branded";
+
+/// Brands the Linux executables of a checked-out commit once, recording what
+/// was changed so later runs and reinstalls can skip the walk.
+pub fn ensure_branded(commit_dir: &Path) {
+    let record = commit_dir.join(BRAND_RECORD);
+    if record.exists() {
+        return;
+    }
+    println!("🏷️  Branding Linux ELF executables in {:?}...", commit_dir);
+    let (branded, failed) = elf::brand_tree(&commit_dir.join("files"));
+    for (path, err) in &failed {
+        eprintln!("⚠️  Could not brand {:?}: {}", path, err);
+    }
+    let mut content = String::new();
+    for path in &branded {
+        content.push_str(&path.to_string_lossy());
+        content.push('\n');
+    }
+    if let Err(e) = fs::write(&record, content) {
+        eprintln!("⚠️  Could not write {:?}: {}", record, e);
+    }
+    println!("🏷️  Branded {} executables", branded.len());
+}
+
+/// Runs one ostree step, failing unless it exits successfully.
+fn run_ostree(ostree_bin: &str, args: &[&str]) -> Result<()> {
+    let status = Command::new(ostree_bin).args(args).status().map_err(|e| Error::spawn(ostree_bin, e))?;
+    if !status.success() {
+        return Err(Error::Ostree(format!("ostree {} failed ({})", args[0], status)));
+    }
+    Ok(())
+}
+
+/// Pulls and checks out `input` (an app id, a `runtime/...` or `app/...` ref,
+/// or a `.flatpakref` file) and, for apps, their runtime.
+pub fn install(input: &str, installation: Installation, config: &Config) -> Result<()> {
+    let repo_dir = get_repo_dir(config, installation)?;
+    let repo = repo_dir.to_string_lossy().to_string();
+    let ostree_bin = find_ostree_binary(config)
+    .ok_or_else(|| Error::Ostree(format!("ostree binary not found; tried {:?} and PATH", config.ostree_binaries)))?;
+
+    let (ref_id, remote_name, remote_url) = if input.ends_with(".flatpakref") {
+        let path = Path::new(input);
+        let conf = Ini::load_from_file(path)
+        .map_err(|e| Error::Metadata(e.to_string()))
+        .with_context(|| format!("reading {}", input))?;
+        let sec = conf
+        .section(Some("Flatpak Ref"))
+        .ok_or_else(|| Error::Metadata(format!("{} has no [Flatpak Ref] section", input)))?;
+        let field = |key: &str| sec.get(key).ok_or_else(|| Error::Metadata(format!("{} has no {}", input, key)));
+        let name = field("Name")?;
+        let url = field("Url")?;
+        let branch = sec.get("Branch").unwrap_or("stable");
+        (format!("app/{}/x86_64/{}", name, branch), "origin".to_string(), url.to_string())
+    } else if input.contains('/') {
+        if input.starts_with("runtime/") || input.starts_with("app/") {
+            (input.to_string(), "flathub".to_string(), config.flathub_url.clone())
+        } else {
+            (format!("runtime/{}", input), "flathub".to_string(), config.flathub_url.clone())
+        }
+    } else {
+        (format!("app/{}/x86_64/stable", input), "flathub".to_string(), config.flathub_url.clone())
+    };
+    let parts: Vec<&str> = ref_id.split('/').collect();
+    if parts.len() != 4 {
+        return Err(Error::Metadata(format!("invalid ref {}", ref_id)));
+    }
+
+    if !repo_dir.join("config").exists() {
+        fs::create_dir_all(&repo_dir).with_context(|| format!("creating {}", repo))?;
+        println!("🌱 Initializing new OSTree repo at {:?}", repo_dir);
+        run_ostree(&ostree_bin, &["init", "--mode=archive-z2", "--repo", &repo])?;
+    }
+
+    run_ostree(&ostree_bin, &["remote", "add", "--if-not-exists", "--no-gpg-verify", "--repo", &repo, &remote_name, &remote_url])?;
+
+    let config_key = format!("remote.{}.gpg-verify", &remote_name);
+    run_ostree(&ostree_bin, &["config", "--repo", &repo, &config_key, "false"])?;
+
+    ensure_repo_config_fixed(&repo_dir, config);
+
+    println!("⬇️  Pulling {} from {}...", ref_id, &remote_name);
+    run_ostree(&ostree_bin, &["pull", "--repo", &repo, &remote_name, &ref_id]).with_context(|| format!("pulling {}", ref_id))?;
+    let output = Command::new(&ostree_bin)
+    .args(["rev-parse", "--repo", &repo, &ref_id])
+    .output()
+    .map_err(|e| Error::spawn(&ostree_bin, e))?;
+    let commit = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || commit.is_empty() {
+        return Err(Error::Ostree(format!("ostree rev-parse {} returned no commit", ref_id)));
+    }
+    let install_base = get_flatpak_dir(config, installation)?.join(parts[0]).join(parts[1]).join(parts[2]).join(parts[3]);
+    let commit_dir = install_base.join(&commit);
+    let active_link = install_base.join("active");
+    if !commit_dir.exists() || fs::read_dir(&commit_dir).ok().is_none_or(|mut d| d.next().is_none()) {
+        println!("📦 Checking out...");
+        if commit_dir.exists() {
+            let _ = fs::remove_dir_all(&commit_dir);
+        }
+        fs::create_dir_all(&install_base).with_context(|| format!("creating {}", install_base.display()))?;
+        run_ostree(&ostree_bin, &["checkout", "--repo", &repo, "--user-mode", &commit, &commit_dir.to_string_lossy()])
+        .with_context(|| format!("checking out {}", ref_id))?;
+    }
+    if active_link.exists() { let _ = fs::remove_file(&active_link); }
+    let _ = symlink(&commit, &active_link);
+    println!("✅ Installed: {}", parts[1]);
+    if parts[0] == "app" {
+        ensure_branded(&commit_dir);
+        let meta_1 = commit_dir.join("metadata");
+        let meta_2 = commit_dir.join("files/metadata");
+        let meta_path = if meta_1.exists() { meta_1 } else { meta_2 };
+        if meta_path.exists() {
+            if let Ok(conf) = Ini::load_from_file(&meta_path) {
+                if let Some(sec) = conf.section(Some("Application")) {
+                    if let Some(rt) = sec.get("runtime") {
+                        println!("🔗 Found Dependency: {}", rt);
+                        install(rt, installation, config).with_context(|| format!("installing runtime {} of {}", rt, parts[1]))?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Ids of the apps in an installation.
+pub fn installed_apps(config: &Config, installation: Installation) -> Result<Vec<String>> {
+    Ok(WalkDir::new(get_flatpak_dir(config, installation)?.join("app"))
+    .min_depth(1)
+    .max_depth(1)
+    .into_iter()
+    .flatten()
+    .map(|entry| entry.file_name().to_string_lossy().to_string())
+    .collect())
+}
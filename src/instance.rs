@@ -0,0 +1,333 @@
+use crate::child::Exit;
+use crate::config::Config;
+use crate::{dbus, devfs};
+use crate::env::shell_quote;
+use crate::error::{Context, Error, Result};
+use crate::mounts::unmount_tree;
+use crate::rctl;
+use crate::sandbox::require_root;
+use ini::Ini;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+/// Name of the keyfile describing an instance inside its state directory.
+const RECORD: &str = "instance";
+/// How long `kill` waits for a supervising `run` to exit after SIGTERM.
+const KILL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A jail started by `run`, as recorded in `<state-dir>/<id>/instance`.
+#[derive(Debug, Clone, Default)]
+pub struct Instance {
+    pub id: String,
+    pub app_id: String,
+    /// The flatvodka process supervising the jail.
+    pub pid: u32,
+    /// The app's process, once spawned.
+    pub app_pid: Option<u32>,
+    pub jail_name: String,
+    pub jid: Option<u32>,
+    pub jail_root: PathBuf,
+    /// Host user the app runs as, and the uid and gid it runs with.
+    pub user: String,
+    pub uid: u32,
+    pub gid: u32,
+    /// Host side of the vnet epair.
+    pub epair: Option<String>,
+    pub mounts: Vec<PathBuf>,
+    /// Shell `export` statements that recreate the app's environment.
+    pub env: String,
+}
+
+/// Reserves the instance id `id` by creating its state directory.
+pub(crate) fn reserve(state_dir: &Path, id: &str) -> Result<()> {
+    let _ = fs::create_dir_all(state_dir);
+    match fs::create_dir(state_dir.join(id)) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Err(Error::Jail(format!("instance {} already exists", id))),
+        Err(e) => Err(e).with_context(|| format!("creating instance state in {}", state_dir.display())),
+    }
+}
+
+impl Instance {
+    pub fn save(&self, state_dir: &Path) {
+        let opt = |v: Option<u32>| v.map(|n| n.to_string()).unwrap_or_default();
+        let mut conf = Ini::new();
+        conf.with_section(Some("Instance"))
+        .set("app-id", self.app_id.as_str())
+        .set("pid", self.pid.to_string())
+        .set("app-pid", opt(self.app_pid))
+        .set("jail-name", self.jail_name.as_str())
+        .set("jid", opt(self.jid))
+        .set("jail-root", self.jail_root.to_string_lossy())
+        .set("user", self.user.as_str())
+        .set("uid", self.uid.to_string())
+        .set("gid", self.gid.to_string())
+        .set("epair", self.epair.clone().unwrap_or_default())
+        .set("mounts", self.mounts.iter().map(|m| format!("{};", m.display())).collect::<String>())
+        .set("env", self.env.as_str());
+        let path = state_dir.join(&self.id).join(RECORD);
+        if let Err(e) = conf.write_to_file(&path) {
+            eprintln!("⚠️  Could not write {:?}: {}", path, e);
+        }
+    }
+
+    pub fn load(state_dir: &Path, id: &str) -> Option<Self> {
+        if !is_valid_id(id) {
+            return None;
+        }
+        let conf = Ini::load_from_file(state_dir.join(id).join(RECORD)).ok()?;
+        let sec = conf.section(Some("Instance"))?;
+        let get = |key: &str| sec.get(key).unwrap_or("").to_string();
+        let non_empty = |key: &str| Some(get(key)).filter(|v| !v.is_empty());
+        Some(Instance {
+            id: id.to_string(),
+            app_id: get("app-id"),
+            pid: get("pid").parse().ok()?,
+            app_pid: get("app-pid").parse().ok(),
+            jail_name: get("jail-name"),
+            jid: get("jid").parse().ok(),
+            jail_root: PathBuf::from(get("jail-root")),
+            user: get("user"),
+            uid: get("uid").parse().ok()?,
+            gid: get("gid").parse().ok()?,
+            epair: non_empty("epair"),
+            mounts: get("mounts").split(';').filter(|m| !m.is_empty()).map(PathBuf::from).collect(),
+            env: get("env"),
+        })
+    }
+
+    /// Whether the supervising flatvodka process is still alive.
+    pub fn is_running(&self) -> bool {
+        signal::kill(Pid::from_raw(self.pid as i32), None).is_ok()
+    }
+
+    /// Stops the jail and drops its rctl rules and epair, leaving the
+    /// filesystem in place.
+    pub fn release(&self) {
+        let _ = Command::new("jail").arg("-r").arg(&self.jail_name).output();
+        rctl::remove(&self.jail_name);
+        if let Some(host_side) = &self.epair {
+            let _ = Command::new("ifconfig").arg(host_side).arg("destroy").output();
+        }
+    }
+
+    /// Releases the jail, then its mounts, the jail root and finally the
+    /// state directory.
+    pub fn teardown(&self, state_dir: &Path) -> bool {
+        self.release();
+        // The proxies' root mounts a directory of the jail, so it goes first.
+        let proxy_root = dbus::proxy_root(&self.jail_root);
+        if !self.jail_root.as_os_str().is_empty() && unmount_tree(&proxy_root) {
+            let _ = fs::remove_dir_all(&proxy_root);
+        }
+        if self.jail_root.as_os_str().is_empty() || !unmount_tree(&self.jail_root) {
+            eprintln!("❌ Could not unmount everything below {:?}; refusing to delete it", self.jail_root);
+            return false;
+        }
+        let _ = fs::remove_dir_all(&self.jail_root);
+        devfs::remove(devfs::ruleset_number(&self.id));
+        let _ = fs::remove_dir_all(state_dir.join(&self.id));
+        true
+    }
+}
+
+/// Owns the instance `run` is setting up or supervising and releases it when
+/// dropped, however `run` ends. Until the app has been started the instance
+/// is torn down completely; afterwards the filesystem is kept for debugging.
+pub(crate) struct Guard {
+    pub record: Instance,
+    state_dir: PathBuf,
+    pub keep_root: bool,
+}
+
+impl Guard {
+    pub fn new(record: Instance, state_dir: &Path) -> Self {
+        record.save(state_dir);
+        Guard { record, state_dir: state_dir.to_path_buf(), keep_root: false }
+    }
+
+    pub fn save(&self) {
+        self.record.save(&self.state_dir);
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if self.keep_root {
+            self.record.release();
+            self.record.epair = None;
+            self.save();
+        } else {
+            self.record.teardown(&self.state_dir);
+        }
+    }
+}
+
+/// Every recorded instance, sorted by id.
+pub fn list(state_dir: &Path) -> Vec<Instance> {
+    let mut instances: Vec<Instance> = fs::read_dir(state_dir)
+    .into_iter()
+    .flatten()
+    .flatten()
+    .filter_map(|e| Instance::load(state_dir, &e.file_name().to_string_lossy()))
+    .collect();
+    instances.sort_by(|a, b| a.id.cmp(&b.id));
+    instances
+}
+
+/// Whether `id` names an entry directly inside the state directory.
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id != "." && id != ".." && !id.contains(['/', '\0'])
+}
+
+/// Looks up a running instance by id, falling back to the only running
+/// instance of an app when given an app id.
+pub fn find(state_dir: &Path, spec: &str) -> Option<Instance> {
+    if !is_valid_id(spec) {
+        eprintln!("❌ {:?} is not an instance or app id", spec);
+        return None;
+    }
+    if let Some(inst) = Instance::load(state_dir, spec) {
+        return Some(inst);
+    }
+    let mut matches: Vec<Instance> = list(state_dir).into_iter().filter(|i| i.app_id == spec && i.is_running()).collect();
+    if matches.len() > 1 {
+        eprintln!("❌ {} has {} running instances; pass an instance id", spec, matches.len());
+        return None;
+    }
+    matches.pop()
+}
+
+/// Numeric id of a running jail.
+pub(crate) fn jail_id(jname: &str) -> Option<u32> {
+    let output = Command::new("jls").arg("-j").arg(jname).arg("jid").output().ok()?;
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+/// A command that runs `program` inside jail `jid` as `uid`:`gid` with no
+/// supplementary groups. jexec would take the credentials from the host's
+/// passwd entry instead.
+pub(crate) fn jail_command(jid: u32, uid: u32, gid: u32, program: &str) -> Command {
+    let mut cmd = Command::new(program);
+    unsafe {
+        cmd.pre_exec(move || {
+            jail_attach(jid as libc::c_int)?;
+            let gid = gid as libc::gid_t;
+            if libc::setgroups(1, &gid) != 0 || libc::setgid(gid) != 0 || libc::setuid(uid as libc::uid_t) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    cmd
+}
+
+/// jail_attach(2), which also changes to the jail's root directory.
+#[cfg(target_os = "freebsd")]
+fn jail_attach(jid: libc::c_int) -> std::io::Result<()> {
+    match unsafe { libc::jail_attach(jid) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "freebsd"))]
+fn jail_attach(_jid: libc::c_int) -> std::io::Result<()> {
+    Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+}
+
+/// Stops an instance and removes everything it left behind. A running
+/// instance is stopped by sending SIGTERM to its supervising `run`, which
+/// forwards it to the app and releases the jail itself; what is left is
+/// removed once that process is gone.
+pub fn kill(config: &Config, spec: &str) -> Result<()> {
+    require_root("kill")?;
+    let state_dir = PathBuf::from(&config.state_dir);
+    let inst = find(&state_dir, spec).ok_or_else(|| Error::NotInstalled(format!("no such instance: {}", spec)))?;
+    println!("🛑 Stopping instance {} ({})", inst.id, inst.app_id);
+    if inst.is_running() {
+        let _ = signal::kill(Pid::from_raw(inst.pid as i32), Signal::SIGTERM);
+        let deadline = Instant::now() + KILL_TIMEOUT;
+        while inst.is_running() {
+            if Instant::now() >= deadline {
+                return Err(Error::Jail(format!("instance {} did not stop; its supervisor (pid {}) is still running", inst.id, inst.pid)));
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+    // The supervisor saved its final state on the way out.
+    let inst = Instance::load(&state_dir, &inst.id).unwrap_or(inst);
+    if !inst.teardown(&state_dir) {
+        return Err(Error::Jail(format!("instance {} was not fully removed", inst.id)));
+    }
+    Ok(())
+}
+
+/// Runs `command` (a shell when empty) in a running instance, as its user and
+/// with the app's environment.
+pub fn enter(config: &Config, spec: &str, command: &[String]) -> Result<Exit> {
+    require_root("enter")?;
+    let state_dir = PathBuf::from(&config.state_dir);
+    let inst = find(&state_dir, spec)
+    .filter(Instance::is_running)
+    .ok_or_else(|| Error::NotInstalled(format!("no running instance: {}", spec)))?;
+    let cmdline = if command.is_empty() {
+        "/bin/sh".to_string()
+    } else {
+        command.iter().map(|a| shell_quote(a)).collect::<Vec<_>>().join(" ")
+    };
+    let jid = inst.jid.ok_or_else(|| Error::Jail(format!("instance {} has no recorded jail id", inst.id)))?;
+    let mut cmd = jail_command(jid, inst.uid, inst.gid, "/bin/sh");
+    cmd.arg("-c");
+    cmd.arg(format!("{}exec {}", inst.env, cmdline));
+    let status = cmd.status().map_err(|e| Error::spawn("/bin/sh", e)).with_context(|| format!("entering jail {}", inst.jail_name))?;
+    Ok(Exit::from_status(status))
+}
+
+/// Removes stopped instances and rctl rules of jails that are gone.
+pub fn clean(config: &Config) {
+    let state_dir = PathBuf::from(&config.state_dir);
+    for inst in list(&state_dir).iter().filter(|i| !i.is_running()) {
+        println!("🧹 Removing stopped instance {} ({})", inst.id, inst.app_id);
+        inst.teardown(&state_dir);
+    }
+    // Jails of running instances, and any `jls` still lists, keep their limits.
+    let running: Vec<String> = list(&state_dir).into_iter().filter(Instance::is_running).map(|i| i.jail_name).collect();
+    for jname in rctl::jails_with_rules().into_iter().filter(|j| !running.contains(j) && jail_id(j).is_none()) {
+        println!("🧹 Removing resource limits of {}", jname);
+        rctl::remove(&jname);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_cannot_leave_the_state_dir() {
+        assert!(is_valid_id("org.test.App-1234"));
+        for id in ["", ".", "..", "../x", "a/b", "/etc"] {
+            assert!(!is_valid_id(id), "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn lookups_reject_traversal() {
+        let root = crate::elf::fixture::scratch_dir("instance-traversal");
+        let state_dir = root.join("state");
+        fs::create_dir_all(state_dir.join("ok")).unwrap();
+        let record = "[Instance]\npid=1\nuid=1001\ngid=4242\n";
+        fs::write(root.join(RECORD), record).unwrap();
+        fs::write(state_dir.join("ok").join(RECORD), record).unwrap();
+        let inst = Instance::load(&state_dir, "ok").unwrap();
+        assert_eq!((inst.uid, inst.gid), (1001, 4242));
+        assert!(Instance::load(&state_dir, "..").is_none());
+        assert!(find(&state_dir, "..").is_none());
+        let _ = fs::remove_dir_all(&root);
+    }
+}
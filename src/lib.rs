@@ -0,0 +1,37 @@
+//! Run Flatpak apps in FreeBSD jails.
+//!
+//! [`install::install`] pulls apps and runtimes into a Flatpak installation,
+//! [`sandbox::run`] launches an installed app in a fresh jail, and
+//! [`instance`] manages the jails that are running.
+
+pub(crate) mod child;
+pub mod config;
+pub(crate) mod dbus;
+pub(crate) mod deps;
+pub(crate) mod devfs;
+pub(crate) mod elf;
+pub mod env;
+pub(crate) mod filesystems;
+pub mod error;
+pub(crate) mod flatpak_info;
+pub(crate) mod gpu;
+pub mod install;
+pub mod instance;
+pub mod metadata;
+pub mod mounts;
+pub(crate) mod permissions;
+pub mod plan;
+pub(crate) mod rctl;
+pub mod sandbox;
+pub(crate) mod theme;
+pub(crate) mod wayland;
+pub(crate) mod xauth;
+
+pub use child::Exit;
+pub use config::Config;
+pub use error::{Error, Result};
+pub use gpu::GpuMode;
+pub use install::{install, Installation};
+pub use permissions::NetworkMode;
+pub use rctl::ResourceLimits;
+pub use sandbox::{run, RunOptions};
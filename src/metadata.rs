@@ -0,0 +1,61 @@
+use crate::config::Config;
+use crate::error::{Context, Error, Result};
+use crate::install::{get_flatpak_dir, SEARCH_ORDER};
+use ini::Ini;
+use std::path::{Path, PathBuf};
+
+/// The `metadata` keyfile of a deployed app.
+pub struct AppMetadata {
+    pub path: PathBuf,
+    pub ini: Ini,
+    /// `[Application] runtime`, e.g. `org.freedesktop.Platform/x86_64/23.08`.
+    pub runtime: String,
+    /// `[Application] command`, the default program to run.
+    pub command: Option<String>,
+}
+
+impl AppMetadata {
+    /// Reads `<deploy>/metadata`.
+    pub fn load(deploy: &Path) -> Result<Self> {
+        let path = deploy.join("metadata");
+        let ini = Ini::load_from_file(&path)
+        .map_err(|e| Error::Metadata(e.to_string()))
+        .with_context(|| format!("reading {}", path.display()))?;
+        let app_sec = ini
+        .section(Some("Application"))
+        .ok_or_else(|| Error::Metadata(format!("{} has no [Application] section", path.display())))?;
+        let runtime = app_sec
+        .get("runtime")
+        .ok_or_else(|| Error::Metadata(format!("{} names no runtime", path.display())))?
+        .to_string();
+        let command = app_sec.get("command").map(str::to_string);
+        Ok(AppMetadata { path, ini, runtime, command })
+    }
+
+    /// The runtime's id and branch.
+    pub fn runtime_id_and_branch(&self) -> Result<(&str, &str)> {
+        match self.runtime.split('/').collect::<Vec<_>>()[..] {
+            [id, _arch, branch] => Ok((id, branch)),
+            _ => Err(Error::Metadata(format!("invalid runtime {} in {}", self.runtime, self.path.display()))),
+        }
+    }
+}
+
+/// The `overrides` directories of all installations, from lowest to highest
+/// precedence.
+pub fn overrides_dirs(config: &Config) -> Result<Vec<PathBuf>> {
+    SEARCH_ORDER.iter().rev().map(|&i| Ok(get_flatpak_dir(config, i)?.join("overrides"))).collect()
+}
+
+/// Profiles named by `[Injection] profiles=` in the override files, per-app
+/// file first; the built-in `default` profile when none are set.
+pub fn override_inject_profiles(overrides_dirs: &[PathBuf], app_id: &str) -> Vec<String> {
+    for file in overrides_dirs.iter().rev().flat_map(|d| [d.join(app_id), d.join("global")]) {
+        if let Ok(conf) = Ini::load_from_file(file) {
+            if let Some(profiles) = conf.section(Some("Injection")).and_then(|s| s.get("profiles")) {
+                return profiles.split(';').map(str::trim).filter(|p| !p.is_empty()).map(str::to_string).collect();
+            }
+        }
+    }
+    vec!["default".to_string()]
+}
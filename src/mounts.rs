@@ -0,0 +1,187 @@
+use crate::error::{Context, Error, Result};
+use std::fs;
+use std::os::unix::fs::lchown;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Mounts `source` of type `fstype` on the existing `target`.
+pub fn sys_mount(fstype: &str, source: &str, target: &Path, ro: bool) -> Result<()> {
+    let mut cmd = Command::new("/sbin/mount");
+    cmd.arg("-t").arg(fstype);
+    if ro {
+        cmd.arg("-o").arg("ro");
+    }
+    cmd.arg(source).arg(target);
+    let status = cmd.status().map_err(|e| Error::spawn("/sbin/mount", e))?;
+    if !status.success() {
+        return Err(Error::Mount(format!("mount -t {} {} {} failed ({})", fstype, source, target.display(), status)));
+    }
+    Ok(())
+}
+
+/// Mount points at or below `root`, as listed by `mount -p`.
+pub fn mounts_under(root: &Path) -> Vec<PathBuf> {
+    let Ok(output) = Command::new("mount").arg("-p").output() else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&output.stdout)
+    .lines()
+    .filter_map(|l| l.split_whitespace().nth(1))
+    .map(|t| PathBuf::from(t.replace("\\040", " ")))
+    .filter(|t| t.starts_with(root))
+    .collect()
+}
+
+/// Force-unmounts `root` and every mount below it, deepest first. Returns
+/// false if anything is still mounted afterwards.
+pub fn unmount_tree(root: &Path) -> bool {
+    let mut targets = mounts_under(root);
+    targets.sort_by_key(|t| std::cmp::Reverse(t.components().count()));
+    for target in targets {
+        let _ = Command::new("umount").arg("-f").arg(&target).output();
+    }
+    mounts_under(root).is_empty()
+}
+
+/// Creates the mount point `path` below `root`, a file for file mounts, and
+/// returns it. Parts of the jail are writable by the app, so nothing on the
+/// way is followed: a symlink is refused, and so is a mount point that does
+/// not resolve to itself below `root`.
+pub fn make_mount_point(root: &Path, path: &Path, file: bool) -> Result<PathBuf> {
+    let refuse = |why: String| Err(Error::Mount(format!("not mounting on {}: {}", path.display(), why)));
+    let names: Vec<_> = path.components().filter(|c| *c != Component::RootDir).collect();
+    let mut target = root.to_path_buf();
+    for (i, component) in names.iter().enumerate() {
+        let Component::Normal(name) = component else {
+            return refuse("not a plain path".to_string());
+        };
+        target.push(name);
+        let last = i + 1 == names.len();
+        match fs::symlink_metadata(&target) {
+            Ok(meta) if meta.file_type().is_symlink() => return refuse(format!("{} is a symlink", target.display())),
+            Ok(meta) if !last && !meta.is_dir() => return refuse(format!("{} is not a directory", target.display())),
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let created = if last && file { fs::File::create(&target).map(drop) } else { fs::create_dir(&target) };
+                created.with_context(|| format!("creating mount point {}", target.display()))?;
+            }
+            Err(e) => return Err(e).with_context(|| format!("checking {}", target.display())),
+        }
+    }
+    let resolved = fs::canonicalize(&target).with_context(|| format!("resolving {}", target.display()))?;
+    let root = fs::canonicalize(root).with_context(|| format!("resolving {}", root.display()))?;
+    if resolved != root.join(path.strip_prefix("/").unwrap_or(path)) {
+        return refuse(format!("it resolves to {}", resolved.display()));
+    }
+    Ok(target)
+}
+
+pub fn mount_bind(source: &Path, target: &Path, ro: bool) -> Result<()> {
+    sys_mount("nullfs", &source.to_string_lossy(), target, ro)
+}
+
+pub fn make_dir(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    fs::create_dir_all(path).with_context(|| format!("creating {}", path.display()))
+}
+
+/// Creates the missing directories of `path` below `base` and hands only
+/// those to `uid`:`gid`. Existing directories keep their owner, and a symlink
+/// anywhere below `base` is refused rather than followed.
+pub fn make_owned_dir(base: &Path, path: &Path, uid: u32, gid: u32) -> Result<()> {
+    let refuse = |why: &str| Err(Error::Io(std::io::Error::other(format!("not creating {}: {}", path.display(), why))));
+    let Ok(rel) = path.strip_prefix(base) else {
+        return refuse(&format!("outside {}", base.display()));
+    };
+    let mut dir = base.to_path_buf();
+    for component in rel.components() {
+        let std::path::Component::Normal(name) = component else {
+            return refuse("not a plain path");
+        };
+        dir.push(name);
+        match fs::symlink_metadata(&dir) {
+            Ok(meta) if meta.is_dir() => continue,
+            Ok(_) => return refuse(&format!("{} is not a directory", dir.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("checking {}", dir.display())),
+        }
+        fs::create_dir(&dir).with_context(|| format!("creating {}", dir.display()))?;
+        lchown(&dir, Some(uid), Some(gid)).with_context(|| format!("changing the owner of {}", dir.display()))?;
+    }
+    Ok(())
+}
+
+/// Copies the contents of `src` into `dst` with a tar pipe, keeping
+/// permissions, links and special files.
+pub fn copy_tree(src: &Path, dst: &Path) -> Result<()> {
+    let mut tar_create = Command::new("tar")
+    .current_dir(src)
+    .arg("-cf").arg("-").arg(".")
+    .stdout(Stdio::piped())
+    .spawn()
+    .map_err(|e| Error::spawn("tar", e))?;
+    let stdout = tar_create.stdout.take().ok_or_else(|| Error::Io(std::io::ErrorKind::BrokenPipe.into()))?;
+    let extract = Command::new("tar")
+    .current_dir(dst)
+    .arg("-xf").arg("-")
+    .stdin(stdout)
+    .status()
+    .map_err(|e| Error::spawn("tar", e))?;
+    let create = tar_create.wait()?;
+    if !create.success() || !extract.success() {
+        return Err(Error::Io(std::io::Error::other(format!("tar {} -> {} failed", src.display(), dst.display()))));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::fixture::scratch_dir;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn mount_points_are_created_below_the_root() {
+        let root = scratch_dir("mount-point");
+        assert_eq!(make_mount_point(&root, Path::new("/"), false).unwrap(), root);
+        let dir = make_mount_point(&root, Path::new("/home/alice/Downloads"), false).unwrap();
+        assert!(dir.is_dir() && dir == root.join("home/alice/Downloads"));
+        let file = make_mount_point(&root, Path::new("/run/user/1001/bus"), true).unwrap();
+        assert!(file.is_file());
+        // Existing mount points are used as they are.
+        assert_eq!(make_mount_point(&root, Path::new("/run/user/1001/bus"), true).unwrap(), file);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn mount_points_never_follow_symlinks() {
+        let root = scratch_dir("mount-point-symlink");
+        let outside = scratch_dir("mount-point-outside");
+        fs::create_dir_all(root.join("home/alice")).unwrap();
+        symlink(&outside, root.join("home/alice/Downloads")).unwrap();
+        symlink("../..", root.join("home/alice/up")).unwrap();
+        for path in ["/home/alice/Downloads", "/home/alice/Downloads/sub", "/home/alice/up/etc", "/home/../etc"] {
+            assert!(make_mount_point(&root, Path::new(path), false).is_err(), "{}", path);
+        }
+        assert!(make_mount_point(&root, Path::new("/home/alice/Downloads"), true).is_err());
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+        let _ = fs::remove_dir_all(&root);
+        let _ = fs::remove_dir_all(&outside);
+    }
+
+    #[test]
+    fn owned_dirs_are_only_created_below_the_base() {
+        let base = scratch_dir("owned-dir");
+        let outside = scratch_dir("owned-dir-outside");
+        let (uid, gid) = (nix::unistd::getuid().as_raw(), nix::unistd::getgid().as_raw());
+        make_owned_dir(&base, &base.join(".var/app/org.test.App/config"), uid, gid).unwrap();
+        assert!(base.join(".var/app/org.test.App/config").is_dir());
+        symlink(&outside, base.join("link")).unwrap();
+        assert!(make_owned_dir(&base, &base.join("link/sub"), uid, gid).is_err());
+        assert!(make_owned_dir(&base, &outside.join("sub"), uid, gid).is_err());
+        assert!(make_owned_dir(&base, &base.join("a/../b"), uid, gid).is_err());
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+        let _ = fs::remove_dir_all(&base);
+        let _ = fs::remove_dir_all(&outside);
+    }
+}
@@ -0,0 +1,203 @@
+use clap::ValueEnum;
+use ini::Ini;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+/// Effective `[Context]` and bus policies of an app: its metadata merged with
+/// the global and per-app override files of each overrides directory, in
+/// that order.
+#[derive(Debug, Default, Clone)]
+pub struct Permissions {
+    pub shared: BTreeSet<String>,
+    pub sockets: BTreeSet<String>,
+    pub devices: BTreeSet<String>,
+    pub features: BTreeSet<String>,
+    /// Raw `filesystems=` entries, negations (`!path`) included, in merge order.
+    pub filesystems: Vec<String>,
+    /// `[Session Bus Policy]`: bus name -> `see`, `talk`, `own` or `none`.
+    pub session_bus_policy: BTreeMap<String, String>,
+    /// `[System Bus Policy]`, likewise.
+    pub system_bus_policy: BTreeMap<String, String>,
+}
+
+impl Permissions {
+    /// `overrides_dirs` are ordered from lowest to highest precedence.
+    pub fn load(metadata: &Ini, overrides_dirs: &[PathBuf], app_id: &str) -> Self {
+        let mut perms = Permissions::default();
+        perms.apply(metadata);
+        for dir in overrides_dirs {
+            for name in ["global", app_id] {
+                if let Ok(conf) = Ini::load_from_file(dir.join(name)) {
+                    perms.apply(&conf);
+                }
+            }
+        }
+        perms
+    }
+
+    fn apply(&mut self, conf: &Ini) {
+        for (section, policy) in [
+            ("Session Bus Policy", &mut self.session_bus_policy),
+            ("System Bus Policy", &mut self.system_bus_policy),
+        ] {
+            for (name, level) in conf.section(Some(section)).into_iter().flat_map(|s| s.iter()) {
+                policy.insert(name.to_string(), level.trim().to_string());
+            }
+        }
+        let Some(ctx) = conf.section(Some("Context")) else {
+            return;
+        };
+        let sets = [
+            ("shared", &mut self.shared),
+            ("sockets", &mut self.sockets),
+            ("devices", &mut self.devices),
+            ("features", &mut self.features),
+        ];
+        for (key, set) in sets {
+            for entry in split_list(ctx.get(key)) {
+                match entry.strip_prefix('!') {
+                    Some(neg) => {
+                        set.remove(neg);
+                    }
+                    None => {
+                        set.insert(entry.to_string());
+                    }
+                }
+            }
+        }
+        self.filesystems.extend(split_list(ctx.get("filesystems")).map(str::to_string));
+    }
+
+    /// `filesystems=` after applying negations: `!entry` drops every earlier
+    /// entry for the same location, whatever its `:ro`/`:rw`/`:create` suffix.
+    pub fn effective_filesystems(&self) -> Vec<String> {
+        let location = |entry: &str| -> String {
+            let trimmed = entry.trim_start_matches('!');
+            match trimmed.rsplit_once(':') {
+                Some((loc, "ro" | "rw" | "create")) => loc.to_string(),
+                _ => trimmed.to_string(),
+            }
+        };
+        let mut effective: Vec<String> = Vec::new();
+        for entry in &self.filesystems {
+            let loc = location(entry);
+            effective.retain(|e| location(e) != loc);
+            if !entry.starts_with('!') {
+                effective.push(entry.clone());
+            }
+        }
+        effective
+    }
+
+    pub fn has_shared(&self, name: &str) -> bool {
+        self.shared.contains(name)
+    }
+
+    pub fn has_device(&self, name: &str) -> bool {
+        self.devices.contains(name)
+    }
+
+    pub fn has_feature(&self, name: &str) -> bool {
+        self.features.contains(name)
+    }
+}
+
+fn split_list(value: Option<&str>) -> impl Iterator<Item = &str> {
+    value
+    .unwrap_or("")
+    .split(';')
+    .map(str::trim)
+    .filter(|s| !s.is_empty())
+}
+
+/// How the jail is attached to the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkMode {
+    /// No IP addresses at all
+    None,
+    /// Share the host's addresses and routing
+    Inherit,
+    /// Own network stack with an epair interface
+    Vnet,
+}
+
+impl NetworkMode {
+    pub fn from_permissions(perms: &Permissions) -> Self {
+        if perms.has_shared("network") {
+            NetworkMode::Inherit
+        } else {
+            NetworkMode::None
+        }
+    }
+}
+
+/// Jail parameters (`jail -c` arguments) derived from the permission model.
+/// `vnet_iface` is the jail side of the epair when `network` is `Vnet`.
+pub fn jail_params(perms: &Permissions, network: NetworkMode, vnet_iface: Option<&str>) -> Vec<String> {
+    let mut params = vec!["host.hostname=flatvodka".to_string()];
+    let devel = perms.has_feature("devel");
+    params.push(format!("securelevel={}", if devel { 1 } else { 3 }));
+    let host_fs = perms.effective_filesystems().iter().any(|f| f == "host" || f.starts_with("host:"));
+    params.push(format!("enforce_statfs={}", if host_fs { 1 } else { 2 }));
+    params.push("children.max=0".to_string());
+    let ipc = if perms.has_shared("ipc") { "inherit" } else { "new" };
+    for key in ["sysvmsg", "sysvsem", "sysvshm"] {
+        params.push(format!("{}={}", key, ipc));
+    }
+    match network {
+        NetworkMode::None => {
+            params.push("ip4=disable".to_string());
+            params.push("ip6=disable".to_string());
+        }
+        NetworkMode::Inherit => {
+            params.push("ip4=inherit".to_string());
+            params.push("ip6=inherit".to_string());
+        }
+        NetworkMode::Vnet => {
+            params.push("vnet".to_string());
+            if let Some(iface) = vnet_iface {
+                params.push(format!("vnet.interface={}", iface));
+            }
+        }
+    }
+    // Raw sockets (ping, traceroute) are opt-in, through `features=raw-sockets`
+    // in an override; Flatpak has no permission for them.
+    if perms.has_feature("raw-sockets") && network != NetworkMode::None {
+        params.push("allow.raw_sockets".to_string());
+    }
+    if perms.has_feature("bluetooth") || perms.has_feature("canbus") {
+        params.push("allow.socket_af".to_string());
+    }
+    if devel {
+        params.push("allow.mlock".to_string());
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::fixture::scratch_dir;
+    use ini::Ini;
+    use std::fs;
+
+    fn enforce_statfs(params: &[String]) -> &str {
+        params.iter().find_map(|p| p.strip_prefix("enforce_statfs=")).unwrap()
+    }
+
+    #[test]
+    fn host_filesystem_relaxes_statfs_until_negated() {
+        let metadata = Ini::load_from_str("[Context]\nfilesystems=host;xdg-download;\n").unwrap();
+        let perms = Permissions::load(&metadata, &[], "org.test.App");
+        assert_eq!(enforce_statfs(&jail_params(&perms, NetworkMode::None, None)), "1");
+
+        let overrides = scratch_dir("permissions-overrides");
+        fs::write(overrides.join("org.test.App"), "[Context]\nfilesystems=!host;\n").unwrap();
+        let perms = Permissions::load(&metadata, std::slice::from_ref(&overrides), "org.test.App");
+        assert_eq!(perms.effective_filesystems(), ["xdg-download"]);
+        assert_eq!(enforce_statfs(&jail_params(&perms, NetworkMode::None, None)), "2");
+        let _ = fs::remove_dir_all(&overrides);
+    }
+}
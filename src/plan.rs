@@ -0,0 +1,201 @@
+use crate::env::Environment;
+use crate::permissions::NetworkMode;
+use crate::sandbox::HostUser;
+use serde::Serialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// A filesystem mounted into the jail.
+#[derive(Debug, Clone, Serialize)]
+pub struct Mount {
+    pub fstype: String,
+    pub source: String,
+    /// Mount point inside the jail.
+    pub target: PathBuf,
+    pub ro: bool,
+    /// The mount point is a file (a socket or library) rather than a directory.
+    pub file: bool,
+    /// Whether a failure aborts the launch; optional mounts only warn.
+    pub required: bool,
+}
+
+impl Mount {
+    pub fn nullfs(source: &Path, target: impl Into<PathBuf>, ro: bool) -> Self {
+        Mount {
+            fstype: "nullfs".to_string(),
+            source: source.to_string_lossy().to_string(),
+            target: target.into(),
+            ro,
+            file: false,
+            required: false,
+        }
+    }
+
+    pub fn file(mut self) -> Self {
+        self.file = true;
+        self
+    }
+
+    pub fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+}
+
+/// One change made while populating the jail. Paths are inside the jail
+/// unless noted otherwise.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "step", rename_all = "kebab-case")]
+pub enum Step {
+    Mount(Mount),
+    /// Copies the contents of a host directory into the jail.
+    CopyTree { source: PathBuf, target: PathBuf, required: bool },
+    Dir { path: PathBuf, mode: Option<u32>, owner: Option<(u32, u32)> },
+    /// A directory on the host below `base`, created as `owner` if missing.
+    HostDir { base: PathBuf, path: PathBuf, owner: (u32, u32) },
+    Write { path: PathBuf, content: String, mode: Option<u32> },
+    /// Replaces `path` with a copy of the host file `source`.
+    Copy { source: PathBuf, path: PathBuf },
+    Symlink { path: PathBuf, target: PathBuf },
+    /// Hides every device node below the devfs at `path` not unhidden by
+    /// `rules`, loaded into devfs ruleset `ruleset`.
+    DevfsRules { path: PathBuf, ruleset: u16, rules: Vec<String> },
+    /// A copy of the host Xauthority file `source` with only the cookies for
+    /// display `display`, written when the jail is populated.
+    Xauthority { source: PathBuf, display: String, path: PathBuf, owner: (u32, u32) },
+}
+
+/// An xdg-dbus-proxy serving a bus to the app.
+#[derive(Debug, Clone, Serialize)]
+pub struct DbusProxy {
+    /// `session` or `system`.
+    pub bus: String,
+    /// Host bus address the proxy connects to.
+    pub address: String,
+    /// Socket the proxy listens on, in its own root; the jail sees it at the
+    /// same path.
+    pub path: PathBuf,
+    /// Filtering options; empty for unfiltered access.
+    pub args: Vec<String>,
+}
+
+/// Everything a launch will do, computed without changing the system.
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    pub app_id: String,
+    pub instance_id: String,
+    pub jail_name: String,
+    pub jail_root: PathBuf,
+    /// Host directory of the app's deployed commit.
+    pub app_deploy: PathBuf,
+    pub runtime_deploy: PathBuf,
+    pub user: HostUser,
+    pub network: NetworkMode,
+    /// `jail -c` parameters; the vnet interface is added once it exists.
+    pub jail_params: Vec<String>,
+    pub rctl_rules: Vec<String>,
+    pub steps: Vec<Step>,
+    /// Started once the steps are done, and stopped with the app.
+    pub dbus_proxies: Vec<DbusProxy>,
+    /// Root the proxies are chrooted to, and the steps that populate it.
+    pub proxy_root: PathBuf,
+    pub proxy_steps: Vec<Step>,
+    /// Injection profiles applied once the jail is populated.
+    pub inject_profiles: Vec<String>,
+    /// Objects whose missing libraries are injected from those profiles.
+    pub dependency_entries: Vec<PathBuf>,
+    pub env: Environment,
+    pub argv: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+fn mode(mode: &Option<u32>) -> String {
+    mode.map(|m| format!(" mode {:04o}", m)).unwrap_or_default()
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Mount(m) => write!(
+                f,
+                "mount    {} {} -> {} ({}{}{})",
+                m.fstype,
+                m.source,
+                m.target.display(),
+                if m.ro { "ro" } else { "rw" },
+                if m.file { ", file" } else { "" },
+                if m.required { ", required" } else { "" }
+            ),
+            Step::CopyTree { source, target, .. } => write!(f, "copy     {}/ -> {}", source.display(), target.display()),
+            Step::Dir { path, mode: m, owner } => {
+                write!(f, "mkdir    {}{}", path.display(), mode(m))?;
+                match owner {
+                    Some((uid, gid)) => write!(f, " owner {}:{}", uid, gid),
+                    None => Ok(()),
+                }
+            }
+            Step::HostDir { path, owner, .. } => write!(f, "host dir {} owner {}:{}", path.display(), owner.0, owner.1),
+            Step::Write { path, content, mode: m } => write!(f, "write    {} ({} bytes){}", path.display(), content.len(), mode(m)),
+            Step::Copy { source, path } => write!(f, "copy     {} -> {}", source.display(), path.display()),
+            Step::Symlink { path, target } => write!(f, "symlink  {} -> {}", path.display(), target.display()),
+            Step::DevfsRules { path, ruleset, rules } => write!(f, "devfs    {} ruleset {}: {}", path.display(), ruleset, rules.join(", ")),
+            Step::Xauthority { source, display, path, owner } => write!(
+                f,
+                "xauth    {} (display :{}) -> {} owner {}:{}",
+                source.display(),
+                display,
+                path.display(),
+                owner.0,
+                owner.1
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "App:        {} (instance {})", self.app_id, self.instance_id)?;
+        writeln!(f, "Jail:       {}", self.jail_name)?;
+        writeln!(f, "Jail root:  {}", self.jail_root.display())?;
+        writeln!(f, "App dir:    {}", self.app_deploy.display())?;
+        writeln!(f, "Runtime:    {}", self.runtime_deploy.display())?;
+        writeln!(f, "User:       {} ({}:{}), home {}", self.user.name, self.user.uid, self.user.gid, self.user.jail_home())?;
+        writeln!(f, "Network:    {:?}", self.network)?;
+        writeln!(f, "\nSteps:")?;
+        for step in &self.steps {
+            writeln!(f, "  {}", step)?;
+        }
+        if !self.dbus_proxies.is_empty() {
+            writeln!(f, "\nD-Bus proxies, in {}:", self.proxy_root.display())?;
+            for step in &self.proxy_steps {
+                writeln!(f, "  {}", step)?;
+            }
+            for proxy in &self.dbus_proxies {
+                writeln!(f, "  {}: {} -> {} {}", proxy.bus, proxy.address, proxy.path.display(), proxy.args.join(" "))?;
+            }
+        }
+        writeln!(f, "\nJail parameters:")?;
+        for param in &self.jail_params {
+            writeln!(f, "  {}", param)?;
+        }
+        if !self.rctl_rules.is_empty() {
+            writeln!(f, "\nResource limits:")?;
+            for rule in &self.rctl_rules {
+                writeln!(f, "  {}", rule)?;
+            }
+        }
+        writeln!(f, "\nLibrary injection: {}", self.inject_profiles.join(", "))?;
+        for entry in &self.dependency_entries {
+            writeln!(f, "  check {}", entry.display())?;
+        }
+        writeln!(f, "\nEnvironment:")?;
+        for (key, value) in self.env.vars() {
+            writeln!(f, "  {}={}", key, value)?;
+        }
+        writeln!(f, "\nArgv: {:?}", self.argv)?;
+        for warning in &self.warnings {
+            writeln!(f, "warning: {}", warning)?;
+        }
+        Ok(())
+    }
+}
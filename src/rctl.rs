@@ -0,0 +1,110 @@
+use clap::Args;
+use ini::Ini;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Per-run resource caps, enforced on the jail through rctl(8). Unset values
+/// fall back to the `[Resource Limits]` section of the override files.
+#[derive(Args, Debug, Default, Clone)]
+pub struct ResourceLimits {
+    /// Memory cap for the whole jail, e.g. 2G or 512M
+    #[arg(long, value_parser = parse_size)]
+    pub memory: Option<u64>,
+    /// CPU cap in percent of one core (200 = two cores)
+    #[arg(long)]
+    pub cpu_percent: Option<u32>,
+    /// Maximum number of processes in the jail
+    #[arg(long)]
+    pub max_procs: Option<u32>,
+    /// Maximum number of open files in the jail
+    #[arg(long)]
+    pub open_files: Option<u32>,
+}
+
+impl ResourceLimits {
+    /// Fills unset limits from `overrides/global` and `overrides/<app_id>`,
+    /// the per-app file taking precedence. `overrides_dirs` are ordered from
+    /// lowest to highest precedence.
+    pub fn with_overrides(mut self, overrides_dirs: &[PathBuf], app_id: &str) -> Self {
+        let files = overrides_dirs.iter().rev().flat_map(|d| [d.join(app_id), d.join("global")]);
+        for file in files {
+            let Ok(conf) = Ini::load_from_file(file) else {
+                continue;
+            };
+            let Some(sec) = conf.section(Some("Resource Limits")) else {
+                continue;
+            };
+            let num = |key: &str| sec.get(key).and_then(|v| v.trim().parse::<u32>().ok());
+            self.memory = self.memory.or_else(|| sec.get("memory").and_then(|v| parse_size(v).ok()));
+            self.cpu_percent = self.cpu_percent.or_else(|| num("cpu-percent"));
+            self.max_procs = self.max_procs.or_else(|| num("max-procs"));
+            self.open_files = self.open_files.or_else(|| num("open-files"));
+        }
+        self
+    }
+
+    /// rctl rules for the jail named `jname`.
+    pub fn rules(&self, jname: &str) -> Vec<String> {
+        let mut rules = Vec::new();
+        let mut push = |resource: &str, amount: Option<u64>| {
+            if let Some(amount) = amount {
+                rules.push(format!("jail:{}:{}:deny={}", jname, resource, amount));
+            }
+        };
+        push("memoryuse", self.memory);
+        push("pcpu", self.cpu_percent.map(u64::from));
+        push("maxproc", self.max_procs.map(u64::from));
+        push("openfiles", self.open_files.map(u64::from));
+        rules
+    }
+}
+
+/// Parses a byte size with an optional K/M/G/T suffix (powers of 1024).
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (digits, suffix) = value.split_at(split);
+    let base: u64 = digits.parse().map_err(|_| format!("invalid size: {}", value))?;
+    let shift = match suffix.trim_end_matches(['b', 'B']).to_ascii_lowercase().as_str() {
+        "" => 0,
+        "k" => 10,
+        "m" => 20,
+        "g" => 30,
+        "t" => 40,
+        _ => return Err(format!("invalid size suffix: {}", value)),
+    };
+    base.checked_shl(shift)
+    .filter(|v| v >> shift == base)
+    .ok_or_else(|| format!("size too large: {}", value))
+}
+
+pub fn apply(rules: &[String]) {
+    for rule in rules {
+        let status = Command::new("rctl").arg("-a").arg(rule).status();
+        if !status.map(|s| s.success()).unwrap_or(false) {
+            eprintln!("⚠️  Failed to add rctl rule: {} (is kern.racct.enable=1 set?)", rule);
+        }
+    }
+}
+
+/// Removes every rctl rule attached to the jail `jname`.
+pub fn remove(jname: &str) {
+    let _ = Command::new("rctl").arg("-r").arg(format!("jail:{}", jname)).output();
+}
+
+/// Names of all flatvodka jails that still have rctl rules.
+pub fn jails_with_rules() -> Vec<String> {
+    let Ok(output) = Command::new("rctl").output() else {
+        return Vec::new();
+    };
+    let mut jails: Vec<String> = String::from_utf8_lossy(&output.stdout)
+    .lines()
+    .filter_map(|l| l.strip_prefix("jail:"))
+    .filter_map(|l| l.split(':').next())
+    .filter(|j| j.starts_with("fv_"))
+    .map(str::to_string)
+    .collect();
+    jails.sort();
+    jails.dedup();
+    jails
+}
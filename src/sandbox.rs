@@ -0,0 +1,1025 @@
+use crate::child::{self, Exit};
+use crate::config::{Config, InjectProfile};
+use crate::error::{Context, Error, Result};
+use crate::gpu::{self, GpuMode};
+use crate::install::{ensure_branded, find_deployment, get_flatpak_dir, SEARCH_ORDER};
+use crate::instance::{self, Instance};
+use crate::metadata::{self, AppMetadata};
+use crate::mounts::{copy_tree, make_dir, make_mount_point, make_owned_dir, mounts_under, sys_mount};
+use crate::permissions::{self, NetworkMode, Permissions};
+use crate::plan::{DbusProxy, Mount, Plan, Step};
+use crate::rctl::{self, ResourceLimits};
+use crate::{dbus, deps, devfs, elf, env, filesystems, flatpak_info, theme, wayland, xauth};
+use clap::Args;
+use serde::Serialize;
+use nix::unistd::{chown, gethostname, getuid, Gid, Group, Uid, User};
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// How `run` sets up the jail; also the options of `flatvodka run`.
+#[derive(Args, Debug, Clone)]
+pub struct RunOptions {
+    /// Command to run instead of the app's own (`/app/bin/<name>` or an absolute path)
+    #[arg(long)]
+    pub command: Option<String>,
+    /// Host user (name or uid) the app runs as; defaults to SUDO_UID/SUDO_GID, then the caller
+    #[arg(long)]
+    pub run_as: Option<String>,
+    /// Network attachment; defaults to inherit with share=network, none otherwise
+    #[arg(long, value_enum)]
+    pub network: Option<NetworkMode>,
+    #[command(flatten)]
+    pub limits: ResourceLimits,
+    /// Host GPU drivers exposed to the app
+    #[arg(long, value_enum, default_value_t = GpuMode::Auto)]
+    pub gpu: GpuMode,
+    /// Library injection profile from the config file (repeatable)
+    #[arg(long = "inject", value_name = "PROFILE")]
+    pub inject: Vec<String>,
+    /// Expose the OSS audio devices (/dev/dsp*, /dev/mixer*), like devices=oss
+    #[arg(long)]
+    pub oss: bool,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            command: None,
+            run_as: None,
+            network: None,
+            limits: ResourceLimits::default(),
+            gpu: GpuMode::Auto,
+            inject: Vec::new(),
+            oss: false,
+        }
+    }
+}
+
+/// Reports a failed optional step and carries on.
+pub(crate) fn warn_on_err(result: Result<()>) {
+    if let Err(e) = result {
+        eprintln!("⚠️  {}", e);
+    }
+}
+
+pub(crate) fn require_root(what: &str) -> Result<()> {
+    if !getuid().is_root() {
+        return Err(Error::Permission(format!("{} requires root", what)));
+    }
+    Ok(())
+}
+
+/// The host account an app runs as.
+#[derive(Debug, Clone, Serialize)]
+pub struct HostUser {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: PathBuf,
+}
+
+impl HostUser {
+    pub fn from_passwd(pw: User) -> Self {
+        HostUser {
+            name: pw.name,
+            uid: pw.uid.as_raw(),
+            gid: pw.gid.as_raw(),
+            home: pw.dir,
+        }
+    }
+
+    /// Home directory inside the jail.
+    pub fn jail_home(&self) -> String {
+        format!("/home/{}", self.name)
+    }
+}
+
+/// What planning reads from the host besides the installations and the
+/// config. `Host::current()` is the machine `flatvodka` runs on; tests plan
+/// against a fixed one.
+#[derive(Debug, Clone)]
+pub struct Host {
+    /// Id of the instance being planned.
+    pub instance_id: String,
+    /// Environment of the invoking session.
+    pub env: BTreeMap<String, String>,
+    /// Directory every host path planning checks is read from: system files,
+    /// sockets, and the directories the config names.
+    pub root: PathBuf,
+    /// The user to run as, instead of the one `--run-as` or sudo names.
+    pub user: Option<HostUser>,
+}
+
+impl Host {
+    /// The running host, with a fresh instance id.
+    pub fn current() -> Self {
+        Host {
+            instance_id: flatpak_info::new_instance_id(),
+            env: std::env::vars().collect(),
+            root: PathBuf::from("/"),
+            user: None,
+        }
+    }
+
+    /// A non-empty variable of the session environment.
+    pub fn var(&self, key: &str) -> Option<&str> {
+        self.env.get(key).map(String::as_str).filter(|v| !v.is_empty())
+    }
+
+    /// Where the absolute host path `path` is read from.
+    pub fn path(&self, path: impl AsRef<Path>) -> PathBuf {
+        let path = path.as_ref();
+        self.root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+}
+
+/// The user named by `--run-as`, else the one who invoked sudo, else the one
+/// running flatvodka.
+pub fn resolve_host_user(host: &Host, run_as: Option<&str>) -> Option<HostUser> {
+    if let Some(spec) = run_as {
+        let pw = match spec.parse::<u32>() {
+            Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+            Err(_) => User::from_name(spec),
+        };
+        return pw.ok().flatten().map(HostUser::from_passwd);
+    }
+    let uid = match host.var("SUDO_UID") {
+        Some(uid) => uid.parse::<u32>().ok()?,
+        None => getuid().as_raw(),
+    };
+    let pw = User::from_uid(Uid::from_raw(uid)).ok().flatten()?;
+    let mut user = HostUser::from_passwd(pw);
+    if let Some(gid) = host.var("SUDO_GID").and_then(|g| g.parse::<u32>().ok()) {
+        user.gid = gid;
+    }
+    Some(user)
+}
+
+/// `content` of a passwd or group file with the entry for `key` replaced by
+/// `entry`.
+fn upsert_db_entry(content: &str, passwd: bool, key: &str, entry: &str) -> String {
+    let prefix = format!("{}:", key);
+    let mut out: String = content
+    .lines()
+    .filter(|l| !l.starts_with(&prefix))
+    .map(|l| format!("{}\n", l))
+    .collect();
+    if out.is_empty() && key != "root" {
+        out.push_str(if passwd { "root:x:0:0:root:/root:/bin/sh\n" } else { "root:x:0:\n" });
+    }
+    out.push_str(entry);
+    out.push('\n');
+    out
+}
+
+/// Writes the jail's `/etc/passwd` and `/etc/group`: the runtime's files in
+/// `rt_files` plus an entry for `user`.
+fn user_db_steps(rt_files: &Path, user: &HostUser) -> Vec<Step> {
+    let read = |name: &str| fs::read_to_string(rt_files.join("etc").join(name)).unwrap_or_default();
+    let group_name = Group::from_gid(Gid::from_raw(user.gid))
+    .ok()
+    .flatten()
+    .map(|g| g.name)
+    .unwrap_or_else(|| user.name.clone());
+    let passwd = upsert_db_entry(
+        &read("passwd"),
+        true,
+        &user.name,
+        &format!("{}:x:{}:{}:{}:{}:/bin/sh", user.name, user.uid, user.gid, user.name, user.jail_home()),
+    );
+    let group = upsert_db_entry(&read("group"), false, &group_name, &format!("{}:x:{}:{}", group_name, user.gid, user.name));
+    vec![
+        Step::Dir { path: "/etc".into(), mode: None, owner: None },
+        Step::Write { path: "/etc/passwd".into(), content: passwd, mode: None },
+        Step::Write { path: "/etc/group".into(), content: group, mode: None },
+    ]
+}
+
+/// Creates an epair and brings the host side up. Returns (host, jail) sides.
+pub(crate) fn create_epair() -> Option<(String, String)> {
+    let output = Command::new("ifconfig").arg("epair").arg("create").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let host_side = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let jail_side = format!("{}b", host_side.strip_suffix('a')?);
+    let _ = Command::new("ifconfig").arg(&host_side).arg("up").output();
+    Some((host_side, jail_side))
+}
+
+/// Works out everything `execute` does to launch `app_id`. Reads the
+/// installations and the host but changes nothing, so it needs no root.
+pub fn plan(app_id: &str, argv: Vec<String>, opts: RunOptions, config: &Config) -> Result<Plan> {
+    plan_on(&Host::current(), app_id, argv, opts, config)
+}
+
+/// `plan` against `host` instead of the running machine.
+pub fn plan_on(host: &Host, app_id: &str, argv: Vec<String>, opts: RunOptions, config: &Config) -> Result<Plan> {
+    let RunOptions { command, run_as, network, limits, gpu, inject, oss } = opts;
+    let run_as = run_as.as_deref();
+    let user = host.user.clone().or_else(|| resolve_host_user(host, run_as)).ok_or_else(|| {
+        Error::Permission("could not determine the host user; run through sudo or pass --run-as <user>".to_string())
+    })?;
+    if user.uid == 0 && run_as.is_none() {
+        return Err(Error::Permission("refusing to run the app as root; pass --run-as root to override".to_string()));
+    }
+    let mut warnings = Vec::new();
+    let Some((app_installation, app_deploy)) = find_deployment(config, "app", app_id, "stable", None)? else {
+        return Err(Error::NotInstalled(format!(
+            "app {} is not installed (searched {:?})",
+            app_id,
+            SEARCH_ORDER.into_iter().flat_map(|i| get_flatpak_dir(config, i).ok()).collect::<Vec<_>>()
+        )));
+    };
+    let app_files = app_deploy.join("files");
+    let overrides_dirs = metadata::overrides_dirs(config)?;
+    let meta = AppMetadata::load(&app_deploy)?;
+    let runtime_str = meta.runtime.as_str();
+    let default_cmd = meta.command.as_deref().unwrap_or("sh");
+    let mut perms = Permissions::load(&meta.ini, &overrides_dirs, app_id);
+    if oss {
+        perms.devices.insert("oss".to_string());
+    }
+    let network = network.unwrap_or_else(|| NetworkMode::from_permissions(&perms));
+    let limits = limits.with_overrides(&overrides_dirs, app_id);
+    let profile_names = if inject.is_empty() { metadata::override_inject_profiles(&overrides_dirs, app_id) } else { inject };
+    let inject_profiles: Vec<InjectProfile> = profile_names
+    .iter()
+    .filter_map(|name| {
+        let profile = config.inject_profile(name);
+        if profile.is_none() {
+            warnings.push(format!("unknown injection profile: {}", name));
+        }
+        profile
+    })
+    .collect();
+    let (rt_id, rt_branch) = meta.runtime_id_and_branch()?;
+    let Some((_, rt_deploy)) = find_deployment(config, "runtime", rt_id, rt_branch, Some(app_installation))? else {
+        return Err(Error::NotInstalled(format!("runtime {} is not installed", runtime_str)));
+    };
+    let rt_files = rt_deploy.join("files");
+    if deps::resolve_in_root(&rt_files, Path::new("/bin/sh")).is_none() {
+        return Err(Error::Jail(format!("/bin/sh not found in runtime {}; is it complete?", runtime_str)));
+    }
+    let instance_id = host.instance_id.clone();
+    let jail_root = PathBuf::from(format!("{}{}-{}", config.jail_root_prefix, app_id, instance_id));
+    let jname = format!("fv_{}_{}", app_id.replace(".", "_"), instance_id);
+    let owner = (user.uid, user.gid);
+    let dir = |path: &str| Step::Dir { path: path.into(), mode: None, owner: None };
+
+    let mut steps = vec![
+        Step::Mount(Mount {
+            fstype: "tmpfs".to_string(),
+            source: "tmpfs".to_string(),
+            target: "/".into(),
+            ro: false,
+            file: false,
+            required: true,
+        }),
+        Step::CopyTree { source: rt_files.clone(), target: "/".into(), required: true },
+        dir("/app"),
+        Step::CopyTree { source: app_files.clone(), target: "/app".into(), required: false },
+    ];
+    // Give the merged-/usr layout of the runtime the aliases Linux binaries expect.
+    let in_runtime = |path: &str| rt_files.join(path).exists();
+    if !in_runtime("usr") {
+        steps.push(dir("/usr"));
+    }
+    for name in ["lib", "bin", "share"] {
+        if !in_runtime(&format!("usr/{}", name)) && in_runtime(name) {
+            steps.push(Step::Symlink { path: Path::new("/usr").join(name), target: Path::new("..").join(name) });
+        }
+    }
+    if !in_runtime("lib64") && in_runtime("lib") {
+        steps.push(Step::Symlink { path: "/lib64".into(), target: "lib".into() });
+    }
+    if !in_runtime("etc/machine-id") {
+        let host_machine_id = host.path("/etc/machine-id");
+        steps.push(if host_machine_id.exists() {
+            Step::Copy { source: host_machine_id, path: "/etc/machine-id".into() }
+        } else {
+            Step::Write { path: "/etc/machine-id".into(), content: "5c02456317b34d6983792070381665ea\n".to_string(), mode: None }
+        });
+    }
+    steps.push(dir("/var/lib/dbus"));
+    if !in_runtime("var/lib/dbus/machine-id") {
+        steps.push(Step::Symlink { path: "/var/lib/dbus/machine-id".into(), target: "/etc/machine-id".into() });
+    }
+
+    let run_user = PathBuf::from(format!("/run/user/{}", user.uid));
+    steps.push(dir("/run/host"));
+    steps.push(Step::Dir { path: run_user.clone(), mode: Some(0o700), owner: Some(owner) });
+    // D-Bus proxies create their sockets in `/run/flatpak/bus`, as the user.
+    for name in ["app", "bus", "ld.so.conf.d", "p11-kit"] {
+        let path = Path::new("/run/flatpak").join(name);
+        steps.push(Step::Dir { owner: (path == Path::new(dbus::PROXY_SOCKET_DIR)).then_some(owner), path, mode: None });
+    }
+    // The session bus: the host socket itself with sockets=session-bus,
+    // otherwise a proxy filtering by `[Session Bus Policy]`.
+    let jail_bus = run_user.join("bus");
+    let mut dbus_proxies = Vec::new();
+    let mut session_bus = false;
+    let mut session_bus_proxy = false;
+    let dbus_proxy_found = host.path(&config.dbus_proxy).exists();
+    match dbus::session_bus_address(host, user.uid) {
+        Some(address) => {
+            let full_access = perms.sockets.contains("session-bus");
+            match dbus::socket_path(&address).map(|p| host.path(p)).filter(|p| full_access && p.exists()) {
+                Some(socket) => {
+                    session_bus = true;
+                    steps.push(Step::Mount(Mount::nullfs(&socket, &jail_bus, false).file()));
+                }
+                None if dbus_proxy_found => {
+                    session_bus = true;
+                    session_bus_proxy = !full_access;
+                    let path = Path::new(dbus::PROXY_SOCKET_DIR).join("session");
+                    steps.push(Step::Symlink { path: jail_bus.clone(), target: path.clone() });
+                    dbus_proxies.push(DbusProxy {
+                        bus: "session".to_string(),
+                        address,
+                        path,
+                        args: if full_access { Vec::new() } else { dbus::session_filter(app_id, &perms.session_bus_policy) },
+                    });
+                }
+                None => warnings.push(format!("{} not found; the app gets no session bus", config.dbus_proxy)),
+            }
+        }
+        None => warnings.push("no session bus found; set DBUS_SESSION_BUS_ADDRESS".to_string()),
+    }
+    // Portal calls go out through the session bus; the files the document
+    // portal exports for the app appear under `$XDG_RUNTIME_DIR/doc`.
+    if session_bus {
+        if !dbus::has_service(host, "org.freedesktop.portal.Desktop") {
+            warnings.push("xdg-desktop-portal is not installed; file dialogs and opening links may not work".to_string());
+        }
+        let doc_host = host.path(format!("/var/run/user/{}/doc/by-app/{}", user.uid, app_id));
+        if doc_host.exists() {
+            steps.push(Step::Mount(Mount::nullfs(&doc_host, run_user.join("doc"), false)));
+        } else {
+            warnings.push(format!("document portal not mounted at {}", doc_host.display()));
+        }
+    }
+    // The system bus: the host's with sockets=system-bus, a proxy filtering
+    // by `[System Bus Policy]` when the app has one, and none otherwise.
+    let jail_system_bus = Path::new(dbus::SYSTEM_BUS_SOCKET);
+    let mut system_bus = false;
+    let mut system_bus_proxy = false;
+    if perms.sockets.contains("system-bus") {
+        let dbus_sys = host.path("/var/run/dbus");
+        if dbus_sys.exists() {
+            system_bus = true;
+            steps.push(Step::Mount(Mount::nullfs(&dbus_sys, "/var/run/dbus", false)));
+        }
+    } else if !perms.system_bus_policy.is_empty() {
+        match dbus::system_bus_address(host) {
+            Some(address) if dbus_proxy_found => {
+                system_bus = true;
+                system_bus_proxy = true;
+                let path = Path::new(dbus::PROXY_SOCKET_DIR).join("system");
+                steps.push(dir("/var/run/dbus"));
+                steps.push(Step::Symlink { path: jail_system_bus.to_path_buf(), target: path.clone() });
+                dbus_proxies.push(DbusProxy {
+                    bus: "system".to_string(),
+                    address,
+                    path,
+                    args: dbus::system_filter(&perms.system_bus_policy),
+                });
+            }
+            Some(_) => warnings.push(format!("{} not found; the app gets no system bus", config.dbus_proxy)),
+            None => warnings.push("no system bus found".to_string()),
+        }
+    }
+    let commit_of = |deploy: &Path| {
+        fs::read_link(deploy).map(|c| c.to_string_lossy().to_string()).unwrap_or_default()
+    };
+    let info = flatpak_info::InstanceInfo {
+        app_id,
+        instance_id: &instance_id,
+        arch: "x86_64",
+        branch: "stable",
+        app_commit: &commit_of(&app_deploy),
+        original_app_path: &app_files,
+        runtime_ref: &format!("runtime/{}", runtime_str),
+        runtime_commit: &commit_of(&rt_deploy),
+        runtime_host_path: &rt_files,
+        instance_path: &user.home.join(".var/app").join(app_id),
+        flatpak_version: &config.flatpak_version,
+        session_bus_proxy,
+        system_bus_proxy,
+        perms: &perms,
+    };
+    // Read-only, with the legacy `$XDG_RUNTIME_DIR/flatpak-info` pointing at it.
+    let info = flatpak_info::render(&info);
+    let proxy_root = dbus::proxy_root(&jail_root);
+    let proxy_steps = if dbus_proxies.is_empty() {
+        Vec::new()
+    } else {
+        dbus::proxy_root_steps(host, &config.dbus_proxy, &dbus_proxies, &info, &jail_root)
+    };
+    steps.push(Step::Write { path: "/.flatpak-info".into(), content: info, mode: Some(0o444) });
+    steps.push(Step::Symlink { path: run_user.join("flatpak-info"), target: "/.flatpak-info".into() });
+
+    let fbsd_fonts = host.path(&config.fonts_dir);
+    if fbsd_fonts.exists() {
+        steps.push(dir("/run/host/fonts"));
+        steps.push(Step::Mount(Mount::nullfs(&fbsd_fonts, "/run/host/fonts", true)));
+        let xml_content = format!(r#"<?xml version="1.0"?>
+        <!DOCTYPE fontconfig SYSTEM "fonts.dtd">
+        <fontconfig>
+        <dir>/run/host/fonts</dir>
+        <dir>{}</dir>
+        </fontconfig>
+        "#, config.fonts_dir);
+        steps.push(Step::Write { path: "/run/host/font-dirs.xml".into(), content: xml_content, mode: None });
+    }
+    let (theme_steps, theme_env) = theme::steps(config, host, &user, &rt_deploy, app_installation, &mut warnings);
+    steps.extend(theme_steps);
+    if network != NetworkMode::None {
+        for file in ["resolv.conf", "hosts"] {
+            let host_file = host.path("/etc").join(file);
+            if host_file.exists() {
+                steps.push(Step::Copy { source: host_file, path: Path::new("/etc").join(file) });
+            }
+        }
+    }
+    let host_os_release = host.path("/etc/os-release");
+    if host_os_release.exists() {
+        steps.push(Step::Copy { source: host_os_release, path: "/run/host/os-release".into() });
+    }
+    steps.push(Step::Dir { path: "/tmp".into(), mode: Some(0o1777), owner: None });
+
+    // Wayland with sockets=wayland: the compositor socket, found the way
+    // libwayland-client does, as `wayland-0` in the jail's runtime directory.
+    let mut has_wayland = false;
+    if perms.sockets.contains("wayland") {
+        let wl_host = wayland::host_socket(host, user.uid);
+        if wayland::is_listening(&wl_host) {
+            has_wayland = true;
+            steps.push(Step::Mount(Mount::nullfs(&wl_host, run_user.join(wayland::DEFAULT_DISPLAY), false).file()));
+        } else {
+            warnings.push(format!("Wayland requested but no compositor listens at {}", wl_host.display()));
+        }
+    }
+    // X11 with sockets=x11, or with fallback-x11 when there is no Wayland.
+    // The app only gets the cookies for its own display.
+    let fallback_x11 = perms.sockets.contains("fallback-x11");
+    let x11 = (perms.sockets.contains("x11") || fallback_x11) && !(fallback_x11 && has_wayland);
+    let mut display = None;
+    let mut xauthority = None;
+    if x11 {
+        let x11_host = host.path("/tmp/.X11-unix");
+        if x11_host.exists() {
+            steps.push(Step::Mount(Mount::nullfs(&x11_host, "/tmp/.X11-unix", false)));
+        }
+        match host.var("DISPLAY") {
+            Some(host_display) => {
+                let source = xauth::host_file(host, &user.home);
+                if let Some(number) = xauth::display_number(host_display).filter(|_| source.exists()) {
+                    let path = run_user.join("Xauthority");
+                    steps.push(Step::Xauthority { source, display: number, path: path.clone(), owner });
+                    xauthority = Some(path);
+                }
+                display = Some(host_display.to_string());
+            }
+            None => warnings.push("X11 requested but DISPLAY is not set".to_string()),
+        }
+    }
+    // Audio, with sockets=pulseaudio: the PulseAudio (or pipewire-pulse)
+    // socket and its cookie, and the native PipeWire socket.
+    let mut pulse_server = None;
+    let mut pulse_cookie = None;
+    if perms.sockets.contains("pulseaudio") {
+        let host_runtime = host.path(format!("/var/run/user/{}", user.uid));
+        let host_server = host.var("PULSE_SERVER").and_then(|s| s.split_whitespace().next().map(str::to_string));
+        let host_socket = match &host_server {
+            Some(server) => server.strip_prefix("unix:").or(server.starts_with('/').then_some(server.as_str())).map(|s| host.path(s)),
+            None => Some(host_runtime.join("pulse/native")),
+        };
+        match (host_socket, host_server) {
+            (Some(socket), _) if socket.exists() => {
+                let jail_socket = run_user.join("pulse/native");
+                steps.push(Step::Mount(Mount::nullfs(&socket, &jail_socket, false).file()));
+                pulse_server = Some(format!("unix:{}", jail_socket.display()));
+            }
+            // A remote server is reached over the network as it is.
+            (None, Some(server)) => pulse_server = Some(server),
+            (Some(socket), _) => warnings.push(format!("no PulseAudio socket at {}", socket.display())),
+            (None, None) => {}
+        }
+        let cookie = host
+        .var("PULSE_COOKIE")
+        .map(|c| host.path(c))
+        .into_iter()
+        .chain([user.home.join(".config/pulse/cookie"), user.home.join(".pulse-cookie")])
+        .find(|c| c.exists());
+        if let Some(cookie) = cookie {
+            let jail_cookie = run_user.join("pulse/cookie");
+            steps.push(Step::Mount(Mount::nullfs(&cookie, &jail_cookie, true).file()));
+            pulse_cookie = Some(jail_cookie);
+        }
+        let pipewire = host_runtime.join("pipewire-0");
+        if pipewire.exists() {
+            steps.push(Step::Mount(Mount::nullfs(&pipewire, run_user.join("pipewire-0"), false).file()));
+        }
+    }
+    let atspi = format!("/var/run/xdg/{}/at-spi", user.name);
+    let atspi_host = host.path(&atspi);
+    if atspi_host.exists() {
+        steps.push(Step::Mount(Mount::nullfs(&atspi_host, atspi, false)));
+    }
+
+    steps.extend(user_db_steps(&rt_files, &user));
+    let home_host = user.home.join(".var/app").join(app_id);
+    steps.push(Step::HostDir { base: user.home.clone(), path: home_host.clone(), owner });
+    // `filesystems=` mounts. When they put the real home at the jail home, the
+    // app's data directory is mounted at its usual place inside it instead.
+    let exports = filesystems::exports(&perms.effective_filesystems(), &user, &jail_root, &mut warnings);
+    let home_exposed = exports.iter().any(|e| Path::new(&user.jail_home()).starts_with(&e.target));
+    let app_data = Path::new(&user.jail_home()).join(".var/app").join(app_id);
+    if !home_exposed {
+        steps.push(dir(&user.jail_home()));
+        steps.push(Step::Mount(Mount::nullfs(&home_host, user.jail_home(), false).required()));
+    }
+    for export in &exports {
+        if export.create {
+            steps.push(Step::HostDir { base: user.home.clone(), path: export.source.clone(), owner });
+        }
+        steps.push(Step::Mount(Mount::nullfs(&export.source, &export.target, export.ro)));
+    }
+    if home_exposed {
+        for sub in ["config", "data", "cache"] {
+            steps.push(Step::HostDir { base: user.home.clone(), path: home_host.join(sub), owner });
+        }
+        steps.push(Step::Mount(Mount::nullfs(&home_host, &app_data, false).required()));
+    }
+
+    let pseudo_fs = |fstype: &str, target: &str| {
+        Step::Mount(Mount {
+            fstype: fstype.to_string(),
+            source: fstype.to_string(),
+            target: target.into(),
+            ro: false,
+            file: false,
+            required: true,
+        })
+    };
+    steps.push(pseudo_fs("devfs", "/dev"));
+    let dev_rules = devfs::ruleset(&perms);
+    if !dev_rules.is_empty() {
+        steps.push(Step::DevfsRules { path: "/dev".into(), ruleset: devfs::ruleset_number(&instance_id), rules: dev_rules });
+    }
+    steps.push(pseudo_fs("linprocfs", "/proc"));
+    steps.push(pseudo_fs("linsysfs", "/sys"));
+
+    let vk_icds = gpu::discover_icds(gpu, &config.vulkan);
+    let vk_layers = gpu::discover_layers(gpu, &config.vulkan);
+    for m in vk_icds.iter().chain(&vk_layers).filter(|m| m.host_library.is_none()) {
+        warnings.push(format!(
+            "driver library {} for {} not found on host, relying on the jail's search path",
+            m.library,
+            m.json.display()
+        ));
+    }
+    let (vk_steps, vk_env) = gpu::install_steps(&vk_icds, &vk_layers);
+    if gpu != GpuMode::None {
+        steps.extend(vk_steps);
+    }
+
+    let final_cmd = command.as_deref().unwrap_or(default_cmd);
+    let bin_path = if final_cmd.starts_with("/") {
+        final_cmd.to_string()
+    } else {
+        format!("/app/bin/{}", final_cmd)
+    };
+    let mut ld_library_path = env::JAIL_LD_LIBRARY_PATH.to_string();
+    for profile in &inject_profiles {
+        let target = format!("/{}", profile.target.trim_start_matches('/'));
+        if !ld_library_path.split(':').any(|d| d == target) {
+            ld_library_path = format!("{}:{}", target, ld_library_path);
+        }
+    }
+    let host_bin_path = match Path::new(&bin_path).strip_prefix("/app") {
+        Ok(rest) => Some(app_files.join(rest)),
+        Err(_) => deps::resolve_in_root(&rt_files, Path::new(&bin_path)),
+    };
+    let is_elf = |path: &Path| fs::read(path).map(|d| elf::is_elf(&d)).unwrap_or(false);
+    let mut dependency_entries = vec![PathBuf::from(&bin_path)];
+    if !host_bin_path.is_some_and(|p| is_elf(&p)) {
+        // Wrapper script: take every ELF launcher shipped in /app/bin instead.
+        dependency_entries = fs::read_dir(app_files.join("bin"))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|e| is_elf(&e.path()))
+        .map(|e| PathBuf::from("/app/bin").join(e.file_name()))
+        .collect();
+    }
+    dependency_entries.extend(vk_icds.iter().chain(&vk_layers).filter_map(|m| {
+        let name = m.host_library.as_ref()?.file_name()?;
+        Some(PathBuf::from("/run/host/vulkan/lib").join(name))
+    }));
+
+    let loaders_cache = env::find_pixbuf_loaders(&rt_files).unwrap_or_else(|| env::DEFAULT_PIXBUF_LOADERS.to_string());
+    let mut app_env = env::app_env(app_id, &user.name, &user.jail_home(), user.uid, &ld_library_path, &loaders_cache);
+    if !vk_env.icd_filenames.is_empty() {
+        app_env.set("VK_ICD_FILENAMES", vk_env.icd_filenames.join(":"));
+    }
+    if let Some(layer_path) = &vk_env.layer_path {
+        app_env.set("VK_LAYER_PATH", layer_path.as_str());
+    }
+    if home_exposed {
+        for (key, sub) in [("XDG_CONFIG_HOME", "config"), ("XDG_DATA_HOME", "data"), ("XDG_CACHE_HOME", "cache")] {
+            app_env.set(key, app_data.join(sub).to_string_lossy());
+        }
+    }
+    if !theme_env.data_dirs.is_empty() {
+        let data_dirs = format!("{}:{}", app_env.get("XDG_DATA_DIRS").unwrap_or_default(), theme_env.data_dirs.join(":"));
+        app_env.set("XDG_DATA_DIRS", data_dirs);
+    }
+    if !theme_env.xcursor_path.is_empty() {
+        app_env.set("XCURSOR_PATH", theme_env.xcursor_path.join(":"));
+    }
+    for key in ["XCURSOR_THEME", "XCURSOR_SIZE"] {
+        if let Some(value) = host.var(key) {
+            app_env.set(key, value);
+        }
+    }
+    if let Some(theme) = theme_env.gtk_theme {
+        app_env.set("GTK_THEME", theme);
+    }
+    if has_wayland {
+        app_env.set("WAYLAND_DISPLAY", wayland::DEFAULT_DISPLAY);
+    }
+    if let Some(display) = display {
+        app_env.set("DISPLAY", display);
+    }
+    if let Some(xauthority) = &xauthority {
+        app_env.set("XAUTHORITY", xauthority.to_string_lossy());
+    }
+    if let Some(server) = pulse_server {
+        app_env.set("PULSE_SERVER", server);
+    }
+    if let Some(cookie) = &pulse_cookie {
+        app_env.set("PULSE_COOKIE", cookie.to_string_lossy());
+    }
+    if session_bus {
+        app_env.set("DBUS_SESSION_BUS_ADDRESS", format!("unix:path={}", jail_bus.display()));
+    }
+    if system_bus {
+        app_env.set("DBUS_SYSTEM_BUS_ADDRESS", format!("unix:path={}", jail_system_bus.display()));
+    }
+
+    Ok(Plan {
+        app_id: app_id.to_string(),
+        instance_id,
+        jail_params: permissions::jail_params(&perms, network, None),
+        rctl_rules: limits.rules(&jname),
+        jail_name: jname,
+        jail_root,
+        app_deploy,
+        runtime_deploy: rt_deploy,
+        user,
+        network,
+        steps,
+        dbus_proxies,
+        proxy_root,
+        proxy_steps,
+        inject_profiles: inject_profiles.into_iter().map(|p| p.name).collect(),
+        dependency_entries,
+        env: app_env,
+        argv: [bin_path].into_iter().chain(argv).collect(),
+        warnings,
+    })
+}
+
+/// Host path of `path` inside the jail at `jail_root`.
+fn in_jail(jail_root: &Path, path: &Path) -> PathBuf {
+    jail_root.join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Carries out one step of a plan below `jail_root`.
+fn apply_step(jail_root: &Path, step: &Step) -> Result<()> {
+    let set_mode = |path: &Path, mode: &Option<u32>| {
+        if let Some(mode) = mode {
+            let _ = fs::set_permissions(path, fs::Permissions::from_mode(*mode));
+        }
+    };
+    match step {
+        Step::Mount(m) => {
+            println!("🔗 Mounting {} {} -> {}", m.fstype, m.source, m.target.display());
+            let result = make_mount_point(jail_root, &m.target, m.file).and_then(|target| sys_mount(&m.fstype, &m.source, &target, m.ro));
+            if m.required {
+                return result;
+            }
+            warn_on_err(result);
+        }
+        Step::CopyTree { source, target, required } => {
+            println!("📦 Copying {}...", source.display());
+            let result = copy_tree(source, &in_jail(jail_root, target)).with_context(|| format!("copying {}", source.display()));
+            if *required {
+                return result;
+            }
+            warn_on_err(result);
+        }
+        Step::Dir { path, mode, owner } => {
+            let target = in_jail(jail_root, path);
+            make_dir(&target)?;
+            set_mode(&target, mode);
+            if let Some((uid, gid)) = owner {
+                let _ = chown(&target, Some(Uid::from_raw(*uid)), Some(Gid::from_raw(*gid)));
+            }
+        }
+        Step::HostDir { base, path, owner: (uid, gid) } => make_owned_dir(base, path, *uid, *gid)?,
+        Step::Write { path, content, mode } => {
+            let target = in_jail(jail_root, path);
+            let _ = fs::remove_file(&target);
+            fs::write(&target, content).with_context(|| format!("writing {}", path.display()))?;
+            set_mode(&target, mode);
+        }
+        Step::Copy { source, path } => {
+            let target = in_jail(jail_root, path);
+            let _ = fs::remove_file(&target);
+            if let Err(e) = fs::copy(source, &target) {
+                eprintln!("⚠️  Could not copy {} to {}: {}", source.display(), path.display(), e);
+            }
+        }
+        Step::Symlink { path, target } => {
+            let _ = symlink(target, in_jail(jail_root, path));
+        }
+        Step::DevfsRules { path, ruleset, rules } => {
+            println!("🔐 Restricting {} to devfs ruleset {} ({} rules)", path.display(), ruleset, rules.len());
+            devfs::apply(&in_jail(jail_root, path), *ruleset, rules)?;
+        }
+        Step::Xauthority { source, display, path, owner: (uid, gid) } => {
+            let hostname = gethostname().map(|h| h.to_string_lossy().to_string()).unwrap_or_default();
+            let all = xauth::parse(&fs::read(source).unwrap_or_default()).unwrap_or_else(|e| {
+                eprintln!("⚠️  Could not read {}: {}", source.display(), e);
+                Vec::new()
+            });
+            let entries = xauth::filter(&all, &hostname, display);
+            if entries.is_empty() {
+                eprintln!("⚠️  No X11 cookie for display :{} in {}", display, source.display());
+            }
+            let target = in_jail(jail_root, path);
+            let _ = fs::remove_file(&target);
+            fs::write(&target, xauth::serialize(&entries)).with_context(|| format!("writing {}", path.display()))?;
+            set_mode(&target, &Some(0o600));
+            let _ = chown(&target, Some(Uid::from_raw(*uid)), Some(Gid::from_raw(*gid)));
+        }
+    }
+    Ok(())
+}
+
+/// Carries out `plan`: populates and starts the jail, runs the app in it as
+/// the host user and waits for it to finish.
+pub fn execute(plan: &Plan, config: &Config) -> Result<Exit> {
+    require_root("run")?;
+    let user = &plan.user;
+    let jail_root = &plan.jail_root;
+    let jname = &plan.jail_name;
+    println!("👤 Detected Host User: {} (UID: {}, GID: {})", user.name, user.uid, user.gid);
+    println!("📂 Using {:?}", plan.app_deploy);
+    // Installs made before branding moved to install time get it done once here.
+    ensure_branded(&plan.app_deploy);
+    let state_dir = PathBuf::from(&config.state_dir);
+    for stale in instance::list(&state_dir).iter().filter(|i| i.app_id == plan.app_id && !i.is_running()) {
+        println!("🧹 Cleaning up previous session {}...", stale.id);
+        stale.teardown(&state_dir);
+    }
+    instance::reserve(&state_dir, &plan.instance_id)?;
+    let mut session = instance::Guard::new(
+        Instance {
+            id: plan.instance_id.clone(),
+            app_id: plan.app_id.clone(),
+            pid: std::process::id(),
+            jail_name: jname.clone(),
+            jail_root: jail_root.clone(),
+            user: user.name.clone(),
+            uid: user.uid,
+            gid: user.gid,
+            ..Instance::default()
+        },
+        &state_dir,
+    );
+    // From here on every return releases the instance through `session`.
+    let old_mask = child::block_signals();
+    make_dir(jail_root)?;
+    println!("🏗️  Populating {}...", jail_root.display());
+    for step in &plan.steps {
+        apply_step(jail_root, step)?;
+    }
+    let mut proxies = Vec::new();
+    if !plan.proxy_steps.is_empty() {
+        make_dir(&plan.proxy_root)?;
+        for step in &plan.proxy_steps {
+            apply_step(&plan.proxy_root, step)?;
+        }
+    }
+    for proxy in &plan.dbus_proxies {
+        println!("🚌 Starting {} bus proxy at {}", proxy.bus, proxy.path.display());
+        warn_on_err(
+            dbus::start_proxy(&config.dbus_proxy, proxy, &plan.app_id, &plan.proxy_root, user.uid, user.gid, old_mask)
+            .map(|p| proxies.push(p)),
+        );
+    }
+    let mut jail_params = plan.jail_params.clone();
+    match plan.network {
+        NetworkMode::Vnet => {
+            let (host_side, jail_side) = create_epair().ok_or_else(|| Error::Jail("failed to create epair interface for vnet".to_string()))?;
+            println!("🔌 Created {} (host) / {} (jail); bridge or address the host side to get connectivity", host_side, jail_side);
+            jail_params.push(format!("vnet.interface={}", jail_side));
+            session.record.epair = Some(host_side);
+        }
+        NetworkMode::Inherit => println!("🌐 Network: inherit"),
+        NetworkMode::None => println!("🚫 Network: none"),
+    }
+    println!("🔒 Creating jail: {}", jname);
+    let jail_status = Command::new("jail")
+    .arg("-c")
+    .arg(format!("name={}", jname))
+    .arg(format!("path={}", jail_root.display()))
+    .args(&jail_params)
+    .arg("persist")
+    .status()
+    .map_err(|e| Error::spawn("jail", e))?;
+    if !jail_status.success() {
+        return Err(Error::Jail(format!("jail -c name={} failed ({})", jname, jail_status)));
+    }
+    session.record.jid = instance::jail_id(jname);
+    println!("✅ Jail created (instance {})", plan.instance_id);
+    if !plan.rctl_rules.is_empty() {
+        println!("📏 Applying resource limits: {:?}", plan.rctl_rules);
+        rctl::apply(&plan.rctl_rules);
+    }
+    let mut gl_lib_dirs = Vec::new();
+    for dir in &config.gl_search_dirs {
+        if Path::new(dir).exists() {
+            if let Ok(entries) = fs::read_dir(dir) {
+                for entry in entries.flatten() {
+                    let path = entry.path();
+                    if let Some(fname) = path.file_name().and_then(|s| s.to_str()) {
+                        if fname.starts_with("libGL") || fname.starts_with("libEGL") || fname.starts_with("libGLX") {
+                            gl_lib_dirs.push(path.to_string_lossy().to_string());
+                        }
+                    }
+                }
+            }
+        }
+    }
+    println!("🛠️  OpenGL libraries found: {:?}", gl_lib_dirs);
+    let inject_profiles: Vec<InjectProfile> = plan.inject_profiles.iter().filter_map(|name| config.inject_profile(name)).collect();
+    for profile in &inject_profiles {
+        println!("💉 Injection profile {}: {:?} -> /{}", profile.name, profile.libs, profile.target.trim_start_matches('/'));
+        deps::inject_profile(jail_root, profile);
+    }
+    let ld_dirs: Vec<&str> = plan.env.get("LD_LIBRARY_PATH").unwrap_or(env::JAIL_LD_LIBRARY_PATH).split(':').collect();
+    let report = deps::inject_missing(jail_root, &plan.dependency_entries, &ld_dirs, &inject_profiles);
+    println!("🔍 Resolved {} shared libraries for {} entry objects", report.resolved.len(), plan.dependency_entries.len());
+    for (lib_name, needed_by) in &report.missing {
+        eprintln!("⚠️  Missing {} (needed by {:?})", lib_name, needed_by);
+    }
+    for path in &report.unreadable {
+        eprintln!("⚠️  Could not read ELF {}", path.display());
+    }
+    let env_exports = plan.env.to_exports();
+    let cmdline = plan.argv.iter().map(|a| env::shell_quote(a)).collect::<Vec<_>>().join(" ");
+    let shell_cmd = format!("{}exec {}", env_exports, cmdline);
+    session.record.mounts = mounts_under(jail_root);
+    session.record.env = env_exports;
+    session.save();
+    if let Some(sig) = child::interrupted() {
+        eprintln!("🛑 Interrupted by {:?} during setup", sig);
+        return Ok(Exit::Signal(sig));
+    }
+    println!("🎬 Executing: {}", cmdline);
+    println!("🔽 Dropping privileges to {} ({}:{})", user.name, user.uid, user.gid);
+    let jid = session.record.jid.ok_or_else(|| Error::Jail(format!("could not find the jail id of {}", jname)))?;
+    // The same uid and gid the jail's directories were made for.
+    let mut cmd = instance::jail_command(jid, user.uid, user.gid, "/bin/sh");
+    cmd.arg("-c");
+    cmd.arg(shell_cmd);
+    child::prepare(&mut cmd, old_mask);
+    let mut app = cmd.spawn().map_err(|e| Error::spawn("/bin/sh", e)).with_context(|| format!("starting {}", cmdline))?;
+    session.keep_root = true;
+    session.record.app_pid = Some(app.id());
+    session.save();
+    let exit = Exit::from_status(child::supervise(&mut app).context("waiting for the app")?);
+    match exit {
+        Exit::Code(code) => println!("🛑 App finished with exit code {}.", code),
+        Exit::Signal(sig) => println!("🛑 App killed by {:?}.", sig),
+    }
+    println!("💾 Filesystem is STILL MOUNTED for debugging at: {} (`flatvodka clean` removes it)", jail_root.display());
+    Ok(exit)
+}
+
+/// Sets up a jail for `app_id`, runs the app in it as the host user and
+/// waits for it to finish.
+pub fn run(app_id: &str, argv: Vec<String>, opts: RunOptions, config: &Config) -> Result<Exit> {
+    require_root("run")?;
+    let plan = plan(app_id, argv, opts, config)?;
+    for warning in &plan.warnings {
+        eprintln!("⚠️  {}", warning);
+    }
+    execute(&plan, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::fixture::{scratch_dir, Fixture};
+
+    /// Writes `content` to `path` below `root`, creating its directory.
+    fn put(root: &Path, path: &str, content: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    /// A system installation with one app and its runtime, and a host with a
+    /// desktop session, all below `root`.
+    fn fake_host(root: &Path) -> (Host, Config) {
+        let flatpak = root.join("flatpak");
+        for (deploy, commit) in [("app/org.test.App/x86_64/stable", "c0ffee"), ("runtime/org.test.Platform/x86_64/24.08", "f00d")] {
+            let dir = flatpak.join(deploy);
+            fs::create_dir_all(dir.join(commit).join("files")).unwrap();
+            symlink(commit, dir.join("active")).unwrap();
+        }
+        let app = "flatpak/app/org.test.App/x86_64/stable/active";
+        put(
+            root,
+            &format!("{}/metadata", app),
+            "[Application]\nname=org.test.App\nruntime=org.test.Platform/x86_64/24.08\ncommand=hello\n\n\
+             [Context]\nshared=network;ipc;\nsockets=x11;pulseaudio;\ndevices=dri;\nfilesystems=xdg-download;\n\n\
+             [Session Bus Policy]\norg.freedesktop.Notifications=talk\n",
+        );
+        Fixture { interp: Some("/lib64/ld-linux-x86-64.so.2"), needed: vec!["libc.so.6"], ..Fixture::default() }
+        .write(&root.join(app).join("files/bin/hello"));
+        let rt = "flatpak/runtime/org.test.Platform/x86_64/24.08/active/files";
+        put(root, &format!("{}/bin/sh", rt), "");
+        put(root, &format!("{}/etc/passwd", rt), "root:x:0:0:root:/root:/bin/sh\n");
+        for file in ["etc/machine-id", "etc/os-release", "etc/resolv.conf", "usr/local/bin/xdg-dbus-proxy", "home/alice/.Xauthority"] {
+            put(root, file, "");
+        }
+        for dir in ["tmp/.X11-unix", "home/alice/Downloads", "var/run/user/1001/doc/by-app/org.test.App"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for socket in ["bus", "pulse/native"] {
+            put(root, &format!("var/run/user/1001/{}", socket), "");
+        }
+        put(root, "home/alice/.config/user-dirs.dirs", "XDG_DOWNLOAD_DIR=\"$HOME/Downloads\"\n");
+
+        // Host paths in the config are looked up below the root, like the rest.
+        let config = Config {
+            user_flatpak_dir: "flatvodka-test-no-such-dir".to_string(),
+            system_flatpak_dir: flatpak.to_string_lossy().to_string(),
+            ..Config::default()
+        };
+        let env = [("DISPLAY", ":0"), ("GTK_THEME", "Adwaita:dark"), ("XCURSOR_SIZE", "24")];
+        let host = Host {
+            instance_id: "1234".to_string(),
+            env: env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            root: root.to_path_buf(),
+            user: Some(HostUser { name: "alice".to_string(), uid: 1001, gid: 4242, home: root.join("home/alice") }),
+        };
+        (host, config)
+    }
+
+    #[test]
+    fn host_user_defaults_to_the_caller() {
+        let host = Host { instance_id: String::new(), env: BTreeMap::new(), root: PathBuf::from("/"), user: None };
+        let uid = getuid().as_raw();
+        if User::from_uid(getuid()).ok().flatten().is_some() {
+            assert_eq!(resolve_host_user(&host, None).map(|u| u.uid), Some(uid));
+        }
+        let sudo = Host { env: [("SUDO_UID".to_string(), uid.to_string()), ("SUDO_GID".to_string(), "4242".to_string())].into(), ..host };
+        if let Some(user) = resolve_host_user(&sudo, None) {
+            assert_eq!((user.uid, user.gid), (uid, 4242));
+        }
+    }
+
+    #[test]
+    fn plan_snapshot() {
+        let root = scratch_dir("plan");
+        let (host, config) = fake_host(&root);
+        let opts = RunOptions { gpu: GpuMode::None, ..RunOptions::default() };
+        let mut plan = plan_on(&host, "org.test.App", Vec::new(), opts, &config).unwrap();
+        // Paths below the scratch root differ between runs; so would the
+        // sizes of the files that name them.
+        let root = root.to_string_lossy().to_string();
+        for step in plan.steps.iter_mut().chain(&mut plan.proxy_steps) {
+            if let Step::Write { content, .. } = step {
+                *content = content.replace(&root, "$ROOT");
+            }
+        }
+        let actual = plan.to_string().replace(&root, "$ROOT");
+        let snapshot = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/snapshots/plan.txt");
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            fs::write(&snapshot, &actual).unwrap();
+        }
+        assert_eq!(actual, fs::read_to_string(&snapshot).unwrap_or_default());
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::config::Config;
+use crate::install::{find_deployment, Installation};
+use crate::plan::{Mount, Step};
+use crate::sandbox::{Host, HostUser};
+use ini::Ini;
+use std::path::{Path, PathBuf};
+
+/// Extension point runtimes declare for GTK 3 themes.
+const GTK3_THEME_EXTENSION: &str = "org.gtk.Gtk3theme";
+
+/// Themes GTK ships itself; they need no extension.
+const BUILTIN_GTK_THEMES: &[&str] = &["Adwaita", "Adwaita-dark", "HighContrast", "HighContrastInverse", "Default", "Emacs"];
+
+/// Environment that makes the app use what `steps` mapped.
+#[derive(Debug, Default)]
+pub struct ThemeEnv {
+    /// Host data directories appended to `XDG_DATA_DIRS`.
+    pub data_dirs: Vec<String>,
+    /// Cursor directories, host ones first.
+    pub xcursor_path: Vec<String>,
+    /// Host theme to select, when its extension is installed.
+    pub gtk_theme: Option<String>,
+}
+
+/// The host's GTK theme: `GTK_THEME` without its variant, else
+/// `gtk-theme-name` from `~/.config/gtk-3.0/settings.ini`.
+pub fn host_gtk_theme(host: &Host, home: &Path) -> Option<String> {
+    if let Some(theme) = host.var("GTK_THEME") {
+        return theme.split(':').next().map(str::to_string);
+    }
+    let settings = Ini::load_from_file(home.join(".config/gtk-3.0/settings.ini")).ok()?;
+    let name = settings.section(Some("Settings"))?.get("gtk-theme-name")?;
+    Some(name.trim().trim_matches('"').to_string()).filter(|n| !n.is_empty())
+}
+
+/// Where the runtime at `rt_deploy` mounts `org.gtk.Gtk3theme.<theme>`, the
+/// data directory GTK finds it in (`/share/runtime/share` for the usual
+/// `share/runtime/share/themes`), and the branch it wants; `None` if it has
+/// no such extension point.
+fn gtk3_theme_dir(rt_deploy: &Path, theme: &str) -> Option<(PathBuf, Option<PathBuf>, String)> {
+    let metadata = Ini::load_from_file(rt_deploy.join("metadata")).ok()?;
+    let ext = metadata.section(Some(format!("Extension {}", GTK3_THEME_EXTENSION)))?;
+    let themes = Path::new("/").join(ext.get("directory")?);
+    let data_dir = themes.parent().filter(|_| themes.ends_with("themes")).map(Path::to_path_buf);
+    let mut dir = themes.join(theme);
+    if let Some(suffix) = ext.get("subdirectory-suffix") {
+        dir.push(suffix);
+    }
+    Some((dir, data_dir, ext.get("version").unwrap_or("3.22").to_string()))
+}
+
+/// Steps that map the host icon, theme and cursor directories read-only
+/// under `/run/host`, and mount the GTK 3 theme extension matching the host
+/// theme if one is installed.
+pub fn steps(
+    config: &Config,
+    host: &Host,
+    user: &HostUser,
+    rt_deploy: &Path,
+    installation: Installation,
+    warnings: &mut Vec<String>,
+) -> (Vec<Step>, ThemeEnv) {
+    let mut steps = Vec::new();
+    let mut env = ThemeEnv::default();
+    let user_share = user.home.join(".local/share");
+    let dirs = [
+        (user.home.join(".icons"), "/run/host/user-icons"),
+        (user_share.join("icons"), "/run/host/user-share/icons"),
+        (user_share.join("themes"), "/run/host/user-share/themes"),
+        (host.path(&config.icons_dir), "/run/host/share/icons"),
+        (host.path(&config.themes_dir), "/run/host/share/themes"),
+        (host.path(&config.cursors_dir), "/run/host/cursors"),
+    ];
+    for (source, target) in dirs {
+        if !source.is_dir() {
+            continue;
+        }
+        steps.push(Step::Dir { path: target.into(), mode: None, owner: None });
+        steps.push(Step::Mount(Mount::nullfs(&source, target, true)));
+        if let Some(data_dir) = target.strip_suffix("/icons").or(target.strip_suffix("/themes")) {
+            if !env.data_dirs.iter().any(|d| d == data_dir) {
+                env.data_dirs.push(data_dir.to_string());
+            }
+        }
+        if !target.ends_with("/themes") {
+            env.xcursor_path.push(target.to_string());
+        }
+    }
+    if !env.xcursor_path.is_empty() {
+        env.xcursor_path.extend(["/app/share/icons".to_string(), "/usr/share/icons".to_string()]);
+    }
+    let Some(theme) = host_gtk_theme(host, &user.home).filter(|t| !BUILTIN_GTK_THEMES.contains(&t.as_str())) else {
+        return (steps, env);
+    };
+    let Some((target, data_dir, branch)) = gtk3_theme_dir(rt_deploy, &theme) else {
+        return (steps, env);
+    };
+    let ext_id = format!("{}.{}", GTK3_THEME_EXTENSION, theme);
+    match find_deployment(config, "runtime", &ext_id, &branch, Some(installation)) {
+        Ok(Some((_, deploy))) => {
+            steps.push(Step::Dir { path: target.clone(), mode: None, owner: None });
+            steps.push(Step::Mount(Mount::nullfs(&deploy.join("files"), target, true)));
+            // Runtimes have no `XDG_DATA_DIRS` entry for it outside Flatpak.
+            env.data_dirs.extend(data_dir.map(|d| d.to_string_lossy().to_string()));
+            env.gtk_theme = Some(theme);
+        }
+        Ok(None) => warnings.push(format!("host GTK theme {}: runtime/{}/x86_64/{} is not installed", theme, ext_id, branch)),
+        Err(e) => warnings.push(format!("host GTK theme {}: {}", theme, e)),
+    }
+    (steps, env)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::fixture::scratch_dir;
+    use std::fs;
+
+    fn runtime(name: &str, extension: &str) -> PathBuf {
+        let deploy = scratch_dir(name);
+        let metadata = format!("[Runtime]\nname=org.test.Platform\n\n[Extension org.gtk.Gtk3theme]\n{}", extension);
+        fs::write(deploy.join("metadata"), metadata).unwrap();
+        deploy
+    }
+
+    #[test]
+    fn theme_extension_dir_and_data_dir() {
+        let deploy = runtime("theme-share", "directory=share/runtime/share/themes\nsubdirectory-suffix=gtk-3.0\nversion=3.22\n");
+        assert_eq!(
+            gtk3_theme_dir(&deploy, "Arc"),
+            Some((
+                PathBuf::from("/share/runtime/share/themes/Arc/gtk-3.0"),
+                Some(PathBuf::from("/share/runtime/share")),
+                "3.22".to_string()
+            ))
+        );
+        let _ = fs::remove_dir_all(&deploy);
+    }
+
+    #[test]
+    fn theme_extension_outside_a_themes_dir_has_no_data_dir() {
+        let deploy = runtime("theme-lib", "directory=lib/gtk-themes\n");
+        assert_eq!(gtk3_theme_dir(&deploy, "Arc"), Some((PathBuf::from("/lib/gtk-themes/Arc"), None, "3.22".to_string())));
+        let _ = fs::remove_dir_all(&deploy);
+    }
+}
@@ -0,0 +1,24 @@
+use crate::sandbox::Host;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+/// Socket name libwayland-client falls back to without `WAYLAND_DISPLAY`.
+pub const DEFAULT_DISPLAY: &str = "wayland-0";
+
+/// The compositor socket a Wayland client of `uid` would connect to: an
+/// absolute `WAYLAND_DISPLAY` as it is, otherwise that name (or
+/// `wayland-0`) in `XDG_RUNTIME_DIR`, which defaults to `/var/run/user/<uid>`.
+pub fn host_socket(host: &Host, uid: u32) -> PathBuf {
+    let display = host.var("WAYLAND_DISPLAY").unwrap_or(DEFAULT_DISPLAY);
+    if display.starts_with('/') {
+        return host.path(display);
+    }
+    let runtime_dir = host.var("XDG_RUNTIME_DIR").map(str::to_string).unwrap_or_else(|| format!("/var/run/user/{}", uid));
+    host.path(runtime_dir).join(display)
+}
+
+/// Whether a compositor accepts connections on `path`.
+pub fn is_listening(path: &Path) -> bool {
+    path.metadata().is_ok_and(|m| m.file_type().is_socket()) && UnixStream::connect(path).is_ok()
+}
@@ -0,0 +1,157 @@
+use crate::sandbox::Host;
+use std::path::PathBuf;
+
+/// `FamilyLocal`: a display on the machine named by the address.
+const FAMILY_LOCAL: u16 = 256;
+/// `FamilyWild`: matches any address.
+const FAMILY_WILD: u16 = 65535;
+
+/// One record of an Xauthority file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub family: u16,
+    pub address: Vec<u8>,
+    /// Display number, as a decimal string.
+    pub number: Vec<u8>,
+    pub name: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+/// The user's Xauthority file: `XAUTHORITY`, or `~/.Xauthority`.
+pub fn host_file(host: &Host, home: &std::path::Path) -> PathBuf {
+    host.var("XAUTHORITY").map(|f| host.path(f)).unwrap_or_else(|| home.join(".Xauthority"))
+}
+
+/// Display number of a local `DISPLAY` (`:0`, `:0.0`, `unix:0`); `None` for
+/// displays reached over the network.
+pub fn display_number(display: &str) -> Option<String> {
+    let rest = display.strip_prefix("unix").unwrap_or(display).strip_prefix(':')?;
+    let number: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+    (!number.is_empty()).then_some(number)
+}
+
+/// Parses an Xauthority file; a truncated record is an error.
+pub fn parse(mut data: &[u8]) -> Result<Vec<Entry>, String> {
+    fn u16_be(data: &mut &[u8]) -> Option<u16> {
+        let (head, rest) = data.split_first_chunk::<2>()?;
+        *data = rest;
+        Some(u16::from_be_bytes(*head))
+    }
+    fn field(data: &mut &[u8]) -> Option<Vec<u8>> {
+        let len = u16_be(data)? as usize;
+        if data.len() < len {
+            return None;
+        }
+        let (value, rest) = data.split_at(len);
+        *data = rest;
+        Some(value.to_vec())
+    }
+    let mut entries = Vec::new();
+    let total = data.len();
+    while !data.is_empty() {
+        let offset = total - data.len();
+        let mut next = || -> Option<Entry> {
+            Some(Entry {
+                family: u16_be(&mut data)?,
+                address: field(&mut data)?,
+                number: field(&mut data)?,
+                name: field(&mut data)?,
+                data: field(&mut data)?,
+            })
+        };
+        match next() {
+            Some(entry) => entries.push(entry),
+            None => return Err(format!("truncated record at offset {}", offset)),
+        }
+    }
+    Ok(entries)
+}
+
+/// Serializes `entries` in the Xauthority format.
+pub fn serialize(entries: &[Entry]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for entry in entries {
+        out.extend_from_slice(&entry.family.to_be_bytes());
+        for field in [&entry.address, &entry.number, &entry.name, &entry.data] {
+            out.extend_from_slice(&(field.len() as u16).to_be_bytes());
+            out.extend_from_slice(field);
+        }
+    }
+    out
+}
+
+/// The entries of this machine (`hostname`) for display `number`, made
+/// wildcards so they still match under the jail's own hostname. Like
+/// flatpak, cookies for other displays and remote hosts are left out.
+pub fn filter(entries: &[Entry], hostname: &str, number: &str) -> Vec<Entry> {
+    entries
+    .iter()
+    .filter(|e| e.family == FAMILY_WILD || (e.family == FAMILY_LOCAL && e.address == hostname.as_bytes()))
+    .filter(|e| e.number == number.as_bytes())
+    .map(|e| Entry { family: FAMILY_WILD, ..e.clone() })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(family: u16, address: &str, number: &str, data: &[u8]) -> Entry {
+        Entry {
+            family,
+            address: address.as_bytes().to_vec(),
+            number: number.as_bytes().to_vec(),
+            name: b"MIT-MAGIC-COOKIE-1".to_vec(),
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let entries = vec![entry(FAMILY_LOCAL, "box", "0", &[1; 16]), entry(0, "\x7f\0\0\x01", "10", &[2; 16])];
+        let bytes = serialize(&entries);
+        assert_eq!(&bytes[..4], &[0x01, 0x00, 0x00, 0x03]);
+        assert_eq!(parse(&bytes), Ok(entries));
+        assert_eq!(parse(&[]), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn filter_keeps_this_machine_and_display() {
+        let entries = vec![
+            entry(FAMILY_LOCAL, "box", "0", b"mine"),
+            entry(FAMILY_LOCAL, "box", "1", b"other display"),
+            entry(FAMILY_LOCAL, "elsewhere", "0", b"other host"),
+            entry(0, "\x7f\0\0\x01", "0", b"internet"),
+            entry(FAMILY_WILD, "", "0", b"wild"),
+            entry(FAMILY_WILD, "", "2", b"wild other display"),
+        ];
+        let kept = filter(&entries, "box", "0");
+        assert_eq!(kept.iter().map(|e| e.data.as_slice()).collect::<Vec<_>>(), [b"mine".as_slice(), b"wild"]);
+        // Made wildcards so the jail's hostname does not matter.
+        assert!(kept.iter().all(|e| e.family == FAMILY_WILD));
+        assert_eq!(kept[0].address, b"box");
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        let bytes = serialize(&[entry(FAMILY_LOCAL, "box", "0", &[7; 16]), entry(FAMILY_LOCAL, "box", "1", &[8; 16])]);
+        let first_len = bytes.len() / 2;
+        for len in [1, 5, first_len + 1, bytes.len() - 1] {
+            assert!(parse(&bytes[..len]).is_err(), "{} bytes", len);
+        }
+        assert_eq!(parse(&bytes[..first_len]).map(|e| e.len()), Ok(1));
+        // A length running past the end of the data.
+        assert!(parse(&[0x01, 0x00, 0xff, 0xff, b'b']).is_err());
+    }
+
+    #[test]
+    fn display_numbers() {
+        assert_eq!(display_number(":0").as_deref(), Some("0"));
+        assert_eq!(display_number(":1.0").as_deref(), Some("1"));
+        assert_eq!(display_number("unix:12").as_deref(), Some("12"));
+        assert_eq!(display_number("remote:0"), None);
+        assert_eq!(display_number("localhost:10.0"), None);
+        assert_eq!(display_number(":"), None);
+        assert_eq!(display_number(""), None);
+    }
+}